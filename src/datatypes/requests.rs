@@ -4,6 +4,41 @@ use serde::{Deserialize, Serialize};
 pub struct StoreRequest {
     pub key: String,
     pub value: String,
+    /// Lifetime of the value in seconds, defaults to and can't exceed 24h
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignedStoreRequest {
+    pub value: String,
+    /// Lifetime of the value in seconds, defaults to and can't exceed 24h
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RawStoreQuery {
+    /// Lifetime of the value in seconds, defaults to and can't exceed 24h
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Replicas that must acknowledge the write, defaults to 1
+    #[serde(default)]
+    pub w: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WriteQuery {
+    /// Replicas that must acknowledge the write, defaults to 1
+    #[serde(default)]
+    pub w: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadQuery {
+    /// Replicas that must answer the read, defaults to 1
+    #[serde(default)]
+    pub r: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,49 @@
+use super::config::CHUNK_SIZE;
+use super::key::Key;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+// prefix of every manifest value, values starting with it are always stored as a manifest
+// so a plain value can never be mistaken for one
+pub const MANIFEST_MAGIC: [u8; 4] = [0x4b, 0x44, 0x4d, 0x01];
+
+// value stored in place of a large one, listing the immutable chunks it was split into.
+// Manifests too large for a single chunk are chunked in turn, the chunks then hold a manifest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub len: u64,
+    pub nested: bool,
+    pub chunks: Vec<Key>,
+}
+
+pub fn needs_chunking(value: &[u8]) -> bool {
+    value.len() > CHUNK_SIZE || value.starts_with(&MANIFEST_MAGIC)
+}
+
+impl Manifest {
+    pub fn new(value: &[u8], nested: bool) -> Self {
+        Self {
+            len: value.len() as u64,
+            nested,
+            chunks: value.chunks(CHUNK_SIZE).map(Key::hash).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = MANIFEST_MAGIC.to_vec();
+        encoded.extend(
+            bincode::DefaultOptions::new()
+                .serialize(self)
+                .expect("[FAILED] Manifest::encode --> Unable to serialize manifest"),
+        );
+        encoded
+    }
+
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        let payload = value.strip_prefix(&MANIFEST_MAGIC)?;
+        bincode::DefaultOptions::new()
+            .with_limit(payload.len() as u64)
+            .deserialize(payload)
+            .ok()
+    }
+}
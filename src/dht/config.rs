@@ -7,13 +7,120 @@ pub const N_BUCKETS: usize = KEY_LEN * 8;
 // number entries in a list
 pub const K_PARAM: usize = 20;
 
+// number of candidates kept per k-bucket to replace unresponsive nodes
+pub const REPLACEMENT_CACHE_SIZE: usize = K_PARAM;
+
+// consecutive timeouts after which a contact is dropped from the routing table
+pub const MAX_FAILURES: u32 = 3;
+
+// contacts accepted from the same /24 (IPv4) or /64 (IPv6) in a single bucket and in the whole table
+pub const MAX_SUBNET_NODES_PER_BUCKET: usize = 2;
+pub const MAX_SUBNET_NODES_PER_TABLE: usize = 10;
+
+// buckets without a lookup for 1h are refreshed
+pub const REFRESH_INTERVAL: u64 = 60 * 60;
+
+// interval between two checks for buckets to refresh 10min
+pub const REFRESH_CHECK_INTERVAL: u64 = 60 * 10;
+
 // buffer size used for streaming UDP
 pub const BUF_SIZE: usize = 4096 * 2;
 
+// values above 4KiB are split into chunks of that size so every message fits in BUF_SIZE
+pub const CHUNK_SIZE: usize = 4096;
+
+// number of chunks stored or fetched at the same time
+pub const CHUNK_PARALLELISM: usize = 16;
+
+// largest message accepted over the stream side channel, anything above BUF_SIZE goes through it
+pub const MAX_STREAM_SIZE: usize = 64 * 1024 * 1024;
+
+// bytes of streamed messages waiting to be fetched by peers and being fetched from them at once,
+// messages beyond either budget are dropped
+pub const MAX_OUTBOX_BYTES: usize = 256 * 1024 * 1024;
+pub const MAX_FETCH_BYTES: usize = 256 * 1024 * 1024;
+
+// prefix of every binary encoded message, anything else is decoded as JSON
+pub const WIRE_MAGIC: [u8; 2] = [0x4b, 0x44];
+
+// version of the binary encoding, bumped on any incompatible change of the messages
+pub const WIRE_VERSION: u8 = 8;
+
 // response timeout 5000ms
 pub const TIMEOUT: u64 = 5000;
 
+// number of replicas whose values are compared by a value lookup
+pub const VALUE_REPLICAS: usize = 3;
+
+// number of closest nodes a record is stored on, lookups return at most K_PARAM of them
+pub const REPLICATION_FACTOR: usize = 20;
+
+// default number of replicas that must acknowledge a write
+pub const WRITE_QUORUM: usize = 1;
+
+// default number of replicas that must answer a read
+pub const READ_QUORUM: usize = 1;
+
+// requests per second a single address may send of each kind, and the burst it may spend at once
+pub const PING_RATE_LIMIT: (f64, f64) = (10.0, 20.0);
+pub const FIND_RATE_LIMIT: (f64, f64) = (200.0, 400.0);
+pub const STORE_RATE_LIMIT: (f64, f64) = (100.0, 200.0);
+pub const STREAM_RATE_LIMIT: (f64, f64) = (5.0, 10.0);
+
+// datagrams per second a single address may send before they are even decoded, responses included.
+// Above what the limits of every request kind and the responses to ours add up to
+pub const DATAGRAM_RATE_LIMIT: (f64, f64) = (1000.0, 2000.0);
+
+// buckets the rate limiter keeps track of, the full ones are forgotten first and the least
+// recently used ones make room once none is full
+pub const MAX_TRACKED_PEERS: usize = 4096;
+
+// requests handled at the same time, anything above is dropped
+pub const MAX_CONCURRENT_HANDLERS: usize = 256;
+
+// records and bytes other nodes may keep on us for a single owner: the public key of a signed key,
+// otherwise the address (or /64 for IPv6) that sent them
+pub const STORE_QUOTA_RECORDS: usize = 100_000;
+pub const STORE_QUOTA_BYTES: u64 = 256 * 1024 * 1024;
+
 // number of concurrent lookups in node lookup
 pub const ALPHA: usize = 3;
 
+// queries a single lookup path may send before settling for the closest nodes it reached
+pub const MAX_LOOKUP_QUERIES: usize = K_PARAM * 3;
+
+// number of disjoint paths a lookup is split into (S/Kademlia), 1 runs a plain Kademlia lookup.
+// More paths survive more malicious nodes at the cost of more queries
+pub const DISJOINT_PATHS: usize = 1;
+
+// highest sequence number a record may carry, records sent with a higher one are refused. Writes
+// of a key already there keep this one and win on their timestamp, so no replica can lock a key
+// by sending the largest possible version
+pub const MAX_SEQ: u64 = 1 << 48;
+
+// records stamped further than 1h in the future are refused for the same reason
+pub const MAX_CLOCK_SKEW: u64 = 60 * 60;
+
+// records expire after 24h unless republished
+pub const RECORD_TTL: u64 = 60 * 60 * 24;
+
+// deleted keys are remembered for 24h so stale replicas can't bring them back
+pub const TOMBSTONE_TTL: u64 = 60 * 60 * 24;
+
+// held records are pushed to the current k closest nodes every hour
+pub const REPLICATE_INTERVAL: u64 = 60 * 60;
+
+// the original publisher refreshes the lifetime of its records every 24h, or sooner when they
+// would expire before the next replication pass
+pub const REPUBLISH_INTERVAL: u64 = 60 * 60 * 24;
+
+// random delay of up to 5min added to the replication schedule
+pub const SCHEDULE_JITTER: u64 = 60 * 5;
+
+// interval between two sweeps of expired records 60s
+pub const SWEEP_INTERVAL: u64 = 60;
+
+// records stored since the last sync are written through to disk every second
+pub const SYNC_INTERVAL: u64 = 1000;
+
 pub const VERBOSE: bool = false;
\ No newline at end of file
@@ -0,0 +1,30 @@
+use super::config::KEY_LEN;
+use super::key::Key;
+use super::storage::Record;
+
+// keys of immutable records start with this prefix followed by the hex encoded hash of their value
+pub const CONTENT_KEY_PREFIX: &str = "sha256/";
+
+pub fn key_for(hash: &Key) -> String {
+    format!("{}{}", CONTENT_KEY_PREFIX, hex::encode(hash.0))
+}
+
+pub fn is_content_key(key: &str) -> bool {
+    key.starts_with(CONTENT_KEY_PREFIX)
+}
+
+// parses the hex encoded hash given by clients
+pub fn parse_hash(hash: &str) -> Option<Key> {
+    let bytes: [u8; KEY_LEN] = hex::decode(hash).ok()?.try_into().ok()?;
+    Some(Key(bytes))
+}
+
+// records under ordinary keys are always accepted, content keys must match the hash of the value
+// and can't be deleted
+pub fn verify(key: &str, record: &Record) -> bool {
+    if !is_content_key(key) {
+        return true;
+    }
+
+    !record.deleted && key_for(&Key::hash(&record.value)) == key
+}
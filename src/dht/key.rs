@@ -1,15 +1,21 @@
 use super::config::KEY_LEN;
+use discv5::enr::NodeId;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fmt::{Binary, Debug, Error, Formatter};
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Key(pub [u8; KEY_LEN]);
 
 impl Key {
     pub fn new(input: String) -> Self {
+        Self::hash(input.as_bytes())
+    }
+
+    // SHA-256 of arbitrary bytes, used to address immutable values
+    pub fn hash(input: &[u8]) -> Self {
         let mut hasher = Sha256::new();
-        hasher.update(input.as_bytes());
+        hasher.update(input);
 
         // we know that the hash output is going to be 256 bits = 32 bytes
         let result = hasher.finalize();
@@ -21,6 +27,11 @@ impl Key {
 
         Self(hash)
     }
+
+    // DHT ids are the discv5 node ids, so both overlays share one identity
+    pub fn from_node_id(id: &NodeId) -> Self {
+        Self(id.raw())
+    }
 }
 
 impl Debug for Key {
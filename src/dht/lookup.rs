@@ -0,0 +1,246 @@
+use super::config::{K_PARAM, MAX_LOOKUP_QUERIES};
+use super::key::{Distance, Key};
+use super::node::Node;
+use super::routing::NodeAndDistance;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    NotQueried,
+    InFlight,
+    Responded,
+    // timed out or answered with garbage, never queried again nor returned
+    Failed,
+}
+
+// candidates of one lookup path ordered by their distance to the target. Only the k closest
+// candidates that haven't failed are ever queried, and the path is over once all of them answered
+#[derive(Debug, Default)]
+struct Path {
+    candidates: BTreeMap<Distance, (Node, State)>,
+    queries: usize,
+}
+
+impl Path {
+    // the k closest candidates still worth waiting for
+    fn closest(&self) -> impl Iterator<Item = (&Distance, &(Node, State))> {
+        self.candidates
+            .iter()
+            .filter(|(_, (_, state))| *state != State::Failed)
+            .take(K_PARAM)
+    }
+
+    fn is_done(&self) -> bool {
+        let exhausted = self.queries >= MAX_LOOKUP_QUERIES;
+
+        self.closest().all(|(_, (_, state))| match state {
+            State::Responded => true,
+            State::NotQueried => exhausted,
+            _ => false,
+        })
+    }
+
+    fn responded(&self) -> impl Iterator<Item = NodeAndDistance> + '_ {
+        self.candidates
+            .iter()
+            .filter(|(_, (_, state))| *state == State::Responded)
+            .map(|(distance, (node, _))| NodeAndDistance(node.clone(), *distance))
+    }
+}
+
+// iterative lookup split into disjoint paths as in S/Kademlia: the seeds are dealt out between
+// the paths and a node queried by one path is dropped by the others, so a malicious node can only
+// steer the path that reached it first
+#[derive(Debug)]
+pub struct Lookup {
+    target: Key,
+    paths: Vec<Path>,
+    // path each queried node belongs to
+    claimed: HashMap<Key, usize>,
+}
+
+impl Lookup {
+    pub fn new(target: Key, mut seeds: Vec<NodeAndDistance>, paths: usize) -> Self {
+        let mut lookup = Self {
+            target,
+            paths: (0..paths.max(1)).map(|_| Path::default()).collect(),
+            claimed: HashMap::new(),
+        };
+
+        seeds.sort_by_key(|entry| entry.1);
+        for (i, seed) in seeds.into_iter().enumerate() {
+            let path = i % lookup.paths.len();
+            lookup.add(path, vec![seed]);
+        }
+
+        lookup
+    }
+
+    pub fn paths(&self) -> usize {
+        self.paths.len()
+    }
+
+    // the distance a peer reports is ignored, a node could otherwise pass for the closest one
+    fn add(&mut self, path: usize, entries: Vec<NodeAndDistance>) {
+        for NodeAndDistance(node, _) in entries {
+            let distance = Distance::new(&node.id, &self.target);
+            if self
+                .claimed
+                .get(&node.id)
+                .is_some_and(|owner| *owner != path)
+            {
+                continue;
+            }
+
+            self.paths[path]
+                .candidates
+                .entry(distance)
+                .or_insert((node, State::NotQueried));
+        }
+    }
+
+    // the closest candidate of the path not queried yet, marked in flight
+    pub fn next(&mut self, path: usize) -> Option<NodeAndDistance> {
+        loop {
+            let current = &self.paths[path];
+            if current.queries >= MAX_LOOKUP_QUERIES {
+                return None;
+            }
+
+            let (distance, (node, _)) = current
+                .closest()
+                .find(|(_, (_, state))| *state == State::NotQueried)?;
+            let (distance, node) = (*distance, node.clone());
+
+            // another path got to it first
+            if self
+                .claimed
+                .get(&node.id)
+                .is_some_and(|owner| *owner != path)
+            {
+                self.paths[path].candidates.remove(&distance);
+                continue;
+            }
+            self.claimed.insert(node.id.clone(), path);
+
+            let current = &mut self.paths[path];
+            if let Some(candidate) = current.candidates.get_mut(&distance) {
+                candidate.1 = State::InFlight;
+            }
+            current.queries += 1;
+
+            return Some(NodeAndDistance(node, distance));
+        }
+    }
+
+    pub fn in_flight(&self, path: usize) -> usize {
+        self.paths[path]
+            .candidates
+            .values()
+            .filter(|(_, state)| *state == State::InFlight)
+            .count()
+    }
+
+    // records an answer along with the closer nodes it returned
+    pub fn succeeded(
+        &mut self,
+        path: usize,
+        entry: &NodeAndDistance,
+        closer: Vec<NodeAndDistance>,
+    ) {
+        self.set_state(path, entry, State::Responded);
+        self.add(path, closer);
+    }
+
+    pub fn failed(&mut self, path: usize, entry: &NodeAndDistance) {
+        self.set_state(path, entry, State::Failed);
+    }
+
+    fn set_state(&mut self, path: usize, entry: &NodeAndDistance, state: State) {
+        if let Some(candidate) = self.paths[path].candidates.get_mut(&entry.1) {
+            candidate.1 = state;
+        }
+    }
+
+    // done once the k closest candidates of every path answered, or their query budget is spent
+    pub fn is_done(&self) -> bool {
+        self.paths.iter().all(Path::is_done)
+    }
+
+    // the k closest nodes that answered, nearest first. Paths take turns filling the result
+    // so a hijacked path can't crowd out the others with fake close nodes
+    pub fn responded(&self) -> Vec<NodeAndDistance> {
+        let mut paths: Vec<_> = self.paths.iter().map(Path::responded).collect();
+        let mut ret = Vec::new();
+
+        while ret.len() < K_PARAM {
+            let before = ret.len();
+            for path in paths.iter_mut() {
+                if ret.len() >= K_PARAM {
+                    break;
+                }
+                if let Some(entry) = path.next() {
+                    ret.push(entry);
+                }
+            }
+
+            if ret.len() == before {
+                break;
+            }
+        }
+
+        ret.sort_by_key(|entry| entry.1);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::make_node;
+    use super::*;
+
+    fn make_nodes(count: u16, target: &Key) -> Vec<NodeAndDistance> {
+        (0..count)
+            .map(|i| {
+                let node = make_node(1000 + i);
+                let distance = Distance::new(&node.id, target);
+                NodeAndDistance(node, distance)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reported_distances_are_ignored() {
+        let target = Key::hash(b"target");
+        let nodes = make_nodes(2, &target);
+
+        let mut lookup = Lookup::new(target, nodes[..1].to_vec(), 1);
+        let seed = lookup.next(0).unwrap();
+        // the peer claims its contact sits right on the target
+        let NodeAndDistance(node, real) = nodes[1].clone();
+        lookup.succeeded(0, &seed, vec![NodeAndDistance(node, Distance([0; 32]))]);
+
+        assert_eq!(lookup.next(0).unwrap().1, real);
+    }
+
+    #[test]
+    fn paths_never_share_a_node() {
+        let target = Key::hash(b"target");
+        let nodes = make_nodes(40, &target);
+
+        let mut lookup = Lookup::new(target, nodes[..6].to_vec(), 3);
+        let mut queried: HashMap<Key, usize> = HashMap::new();
+        while !lookup.is_done() {
+            for path in 0..lookup.paths() {
+                // every node knows every other node, so all paths hear about the same ones
+                while let Some(entry) = lookup.next(path) {
+                    assert!(queried.insert(entry.0.id.clone(), path).is_none());
+                    lookup.succeeded(path, &entry, nodes.clone());
+                }
+            }
+        }
+
+        assert_eq!(queried.len(), nodes.len());
+        assert_eq!(lookup.responded().len(), K_PARAM);
+    }
+}
@@ -5,4 +5,12 @@ pub mod node;
 pub mod utils;
 pub mod protocol;
 pub mod network;
-pub mod routing;
\ No newline at end of file
+pub mod ratelimit;
+pub mod routing;
+pub mod lookup;
+pub mod storage;
+pub mod signed;
+pub mod content;
+pub mod chunks;
+#[cfg(test)]
+mod testutil;
\ No newline at end of file
@@ -1,25 +1,37 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 
 use super::key::Key;
 use super::node::*;
+use super::ratelimit::{RateLimiter, RequestKind};
 use super::routing::FindValueResult;
 use super::routing::NodeAndDistance;
-use super::config::BUF_SIZE;
+use super::signed::RecordSignature;
+use super::storage::Version;
+use discv5::Enr;
+use super::config::{BUF_SIZE, KEY_LEN, MAX_FETCH_BYTES, MAX_OUTBOX_BYTES, MAX_STREAM_SIZE};
 use super::config::TIMEOUT;
 use super::config::VERBOSE;
+use super::config::{WIRE_MAGIC, WIRE_VERSION};
 use std::collections::HashMap;
-use std::net::UdpSocket;
+use std::net::SocketAddr;
 use std::str;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Ping,
-    Store(String, String),
+    // key, value, time-to-live in seconds, version and signature of the value
+    Store(String, Vec<u8>, u64, Version, Option<RecordSignature>),
     FindNode(Key),
     FindValue(String),
+    // key, time-to-live of the tombstone in seconds, version and signature of the delete
+    Delete(String, u64, Version, Option<RecordSignature>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,6 +39,10 @@ pub enum Response {
     Ping,
     FindNode(Vec<NodeAndDistance>),
     FindValue(FindValueResult),
+    // the record stored or deleted is older than the one held, which is sent back as Value or Deleted
+    Outdated(FindValueResult),
+    // the record wasn't stored, whoever it is charged to is over quota
+    Refused,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,6 +50,9 @@ pub enum Message {
     Abort,
     Request(Request),
     Response(Response),
+    // the actual message didn't fit in a datagram, it can be fetched by its token
+    // from the sender's stream listener: tcp port and length of the encoded message
+    Stream(u16, u64),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,121 +61,669 @@ pub struct RpcMessage {
     pub src: String,
     pub dst: String,
     pub msg: Message,
+    // record of the sender, its node id is the sender's DHT id. Absent in stream announcements
+    #[serde(default)]
+    pub enr: Option<Enr>,
 }
 
 #[derive(Debug)]
 pub struct ReqWrapper {
     pub token: Key,
     pub src: String,
+    pub enr: Option<Enr>,
     pub payload: Request,
+    // encoding the request came in, the response is sent back using the same one
+    pub format: WireFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Binary,
+}
+
+impl std::fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireFormat::Json => f.write_str("json"),
+            WireFormat::Binary => f.write_str("binary"),
+        }
+    }
+}
+
+impl std::str::FromStr for WireFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WireFormat::Json),
+            "binary" => Ok(WireFormat::Binary),
+            _ => Err("bad format"),
+        }
+    }
+}
+
+// counters of the datagrams seen by the receive loop, dropped ones are split by reason
+#[derive(Debug, Default)]
+pub struct RpcStats {
+    pub received: AtomicU64,
+    pub recv_errors: AtomicU64,
+    pub malformed: AtomicU64,
+    pub rejected: AtomicU64,
+    pub unsolicited: AtomicU64,
+    // records ignored because we hold a newer one
+    pub outdated: AtomicU64,
+    // messages sent and fetched over the stream side channel
+    pub streamed_out: AtomicU64,
+    pub streamed_in: AtomicU64,
+    pub stream_errors: AtomicU64,
+    // streamed messages dropped because the outbox or the fetch budget was full
+    pub over_budget: AtomicU64,
+    // requests dropped by the rate limiter, the handler limit and the store quotas
+    pub rate_limited: AtomicU64,
+    pub busy: AtomicU64,
+    pub over_quota: AtomicU64,
+}
+
+impl RpcStats {
+    // bumps the given counter and returns its new value
+    pub fn incr(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // drops are logged on the first one and every thousandth after, a flood would drown the log
+    pub fn should_log(count: u64) -> bool {
+        count == 1 || count.is_multiple_of(1000)
+    }
+}
+
+// encoded messages waiting to be fetched over the stream listener, by token, and their total size
+#[derive(Debug, Default)]
+pub struct Outbox {
+    messages: HashMap<Key, Vec<u8>>,
+    bytes: usize,
+}
+
+impl Outbox {
+    // returns false when the message would take the outbox above MAX_OUTBOX_BYTES
+    fn park(&mut self, token: Key, encoded: Vec<u8>) -> bool {
+        let replaced = self.messages.get(&token).map_or(0, Vec::len);
+        let bytes = self.bytes - replaced + encoded.len();
+        if bytes > MAX_OUTBOX_BYTES {
+            return false;
+        }
+
+        self.bytes = bytes;
+        self.messages.insert(token, encoded);
+        true
+    }
+
+    fn take(&mut self, token: &Key) -> Option<Vec<u8>> {
+        let encoded = self.messages.remove(token)?;
+        self.bytes -= encoded.len();
+        Some(encoded)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Rpc {
     pub socket: Arc<UdpSocket>,
-    pub pending: Arc<Mutex<HashMap<Key, mpsc::Sender<Option<Response>>>>>,
+    // serves the messages too large for a datagram
+    pub listener: Arc<TcpListener>,
+    pub pending: Arc<Mutex<HashMap<Key, oneshot::Sender<Response>>>>,
+    pub outbox: Arc<Mutex<Outbox>>,
+    // one permit per byte of the messages being fetched from peers
+    pub fetches: Arc<Semaphore>,
     pub node: Node,
+    // our current ENR attached to every message, discv5 re-signs it whenever our address changes
+    local_enr: Arc<RwLock<Enr>>,
+    pub stats: Arc<RpcStats>,
+    pub limiter: Arc<Mutex<RateLimiter>>,
+    // encoding used for the requests we send
+    pub format: WireFormat,
 }
 
 impl Rpc {
-    pub fn new(node: Node) -> Self {
-        let socket = UdpSocket::bind(node.get_addr())
-            .expect("[FAILED] Rpc::new --> Error while binding UdpSocket to specified addr");
+    // the socket binds bind_addr while node holds the address we advertise
+    pub async fn new(
+        node: Node,
+        bind_addr: SocketAddr,
+        format: WireFormat,
+    ) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(bind_addr).await.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("unable to bind the DHT socket on {}: {}", bind_addr, e),
+            )
+        })?;
+        // tcp and udp ports are distinct, the listener takes the same one as the socket when it can
+        let listener = TcpListener::bind(socket.local_addr()?).await.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "unable to bind the DHT stream listener on {}: {}",
+                    bind_addr, e
+                ),
+            )
+        })?;
 
-        Self {
+        Ok(Self {
             socket: Arc::new(socket),
+            listener: Arc::new(listener),
             pending: Arc::new(Mutex::new(HashMap::new())),
+            outbox: Arc::new(Mutex::new(Outbox::default())),
+            fetches: Arc::new(Semaphore::new(MAX_FETCH_BYTES)),
+            local_enr: Arc::new(RwLock::new(node.enr.clone())),
             node,
+            stats: Arc::new(RpcStats::default()),
+            limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            format,
+        })
+    }
+
+    // binary messages are laid out as: magic | version (1 byte) | payload length (u32 BE) | bincode payload
+    pub fn encode(msg: &RpcMessage, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Json => serde_json::to_vec(msg)
+                .expect("[FAILED] Rpc::encode --> Unable to serialize message"),
+            WireFormat::Binary => {
+                let payload = bincode::DefaultOptions::new()
+                    .serialize(msg)
+                    .expect("[FAILED] Rpc::encode --> Unable to serialize message");
+
+                let mut encoded = Vec::with_capacity(WIRE_MAGIC.len() + 5 + payload.len());
+                encoded.extend_from_slice(&WIRE_MAGIC);
+                encoded.push(WIRE_VERSION);
+                encoded.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                encoded.extend_from_slice(&payload);
+                encoded
+            }
         }
     }
-    pub fn open(rpc: Rpc, sender: mpsc::Sender<ReqWrapper>) {
-        thread::spawn(move || {
+
+    // anything not starting with the magic prefix is decoded as JSON, as sent by nodes running with
+    // --wire-format json. There is no migration window for older releases: their messages carry no
+    // ENR, version or signature, which every node now requires, so they are dropped as malformed
+    pub fn decode(buf: &[u8]) -> Result<(RpcMessage, WireFormat), String> {
+        if !buf.starts_with(&WIRE_MAGIC) {
+            let payload = str::from_utf8(buf).map_err(|e| format!("invalid utf-8: {}", e))?;
+
+            return serde_json::from_str(payload)
+                .map(|msg| (msg, WireFormat::Json))
+                .map_err(|e| format!("invalid json: {}", e));
+        }
+
+        let header_len = WIRE_MAGIC.len() + 5;
+        if buf.len() < header_len {
+            return Err("truncated header".to_string());
+        }
+
+        let version = buf[WIRE_MAGIC.len()];
+        if version != WIRE_VERSION {
+            return Err(format!("unsupported protocol version {}", version));
+        }
+
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&buf[WIRE_MAGIC.len() + 1..header_len]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+
+        let payload = &buf[header_len..];
+        if payload.len() != len {
+            return Err(format!(
+                "length mismatch, expected {} bytes but got {}",
+                len,
+                payload.len()
+            ));
+        }
+
+        bincode::DefaultOptions::new()
+            .with_limit(len as u64)
+            .deserialize(payload)
+            .map(|msg| (msg, WireFormat::Binary))
+            .map_err(|e| format!("invalid binary payload: {}", e))
+    }
+
+    // the receive loop must outlive any datagram, so bad packets are only logged and counted
+    pub fn open(rpc: Rpc, sender: mpsc::UnboundedSender<ReqWrapper>) {
+        Rpc::serve_streams(rpc.clone());
+
+        tokio::spawn(async move {
             let mut buf = [0u8; BUF_SIZE];
 
             loop {
-                let (len, src_addr) = rpc
-                    .socket
-                    .recv_from(&mut buf)
-                    .expect("[FAILED] Rpc::open --> Failed to receive data from peer");
+                let (len, src_addr) = match rpc.socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        let count = RpcStats::incr(&rpc.stats.recv_errors);
+                        if RpcStats::should_log(count) {
+                            eprintln!(
+                                "[WARNING] Rpc::open --> Failed to receive data from peer ({} so far): {}",
+                                count, e
+                            );
+                        }
+                        continue;
+                    }
+                };
+                RpcStats::incr(&rpc.stats.received);
+
+                // decoding costs more than receiving, a flood is dropped before it gets there.
+                // Responses count as well, whether we asked for them or not
+                if !rpc.allow(src_addr, RequestKind::Datagram) {
+                    continue;
+                }
 
-                let payload =
-                    String::from(str::from_utf8(&buf[..len]).expect(
-                        "[FAILED] Rpc::open --> Unable to parse string from received bytes",
-                    ));
+                let (decoded, format) = match Rpc::decode(&buf[..len]) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        let count = RpcStats::incr(&rpc.stats.malformed);
+                        if RpcStats::should_log(count) {
+                            eprintln!(
+                                "[WARNING] Rpc::open --> Dropping malformed packet from {} ({} so far): {}",
+                                src_addr, count, e
+                            );
+                        }
+                        continue;
+                    }
+                };
+
+                if let Message::Stream(port, size) = decoded.msg {
+                    // fetching is far more expensive than receiving a datagram
+                    if !rpc.allow(src_addr, RequestKind::Stream) {
+                        continue;
+                    }
+                    let rpc = rpc.clone();
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        rpc.fetch_stream(decoded.token, src_addr, port, size, sender)
+                            .await;
+                    });
+                    continue;
+                }
 
-                let mut decoded: RpcMessage = serde_json::from_str(&payload)
-                    .expect("[FAILED] Rpc::open, serde_json --> Unable to decode string payload");
+                if !rpc.dispatch(decoded, format, src_addr, &sender) {
+                    break;
+                }
+            }
+        });
+    }
+
+    // hands a decoded message over to its handler, returns false once the request receiver is gone
+    fn dispatch(
+        &self,
+        mut decoded: RpcMessage,
+        format: WireFormat,
+        src_addr: SocketAddr,
+        sender: &mpsc::UnboundedSender<ReqWrapper>,
+    ) -> bool {
+        decoded.src = src_addr.to_string();
+
+        if VERBOSE {
+            println!(
+                "----------\n[+] Received message: {:?}\n\ttoken: {:?}\n\tsrc: {}\n\tdst: {}\n\tmsg: {:?}\n----------",
+                &decoded.msg, &decoded.token, &decoded.src, &decoded.dst, &decoded.msg
+            );
+        }
+
+        match decoded.msg {
+            Message::Abort => {
+                // nobody on the network gets to shut our receive loop down
+                let count = RpcStats::incr(&self.stats.rejected);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::dispatch --> Rejecting Abort message from {} ({} so far)",
+                        src_addr, count
+                    );
+                }
+            }
+            Message::Stream(..) => {
+                // announcements only travel over udp, a streamed one would chain fetches
+                let count = RpcStats::incr(&self.stats.rejected);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::dispatch --> Rejecting nested stream message from {} ({} so far)",
+                        src_addr, count
+                    );
+                }
+            }
+            Message::Request(req) => {
+                if !self.allow(src_addr, RequestKind::of(&req)) {
+                    return true;
+                }
 
-                decoded.src = src_addr.to_string();
+                let wrapped_req = ReqWrapper {
+                    token: decoded.token,
+                    src: decoded.src,
+                    enr: decoded.enr,
+                    payload: req,
+                    format,
+                };
 
-                if VERBOSE {
-                    println!(
-                        "----------\n[+] Received message: {:?}\n\ttoken: {:?}\n\tsrc: {}\n\tdst: {}\n\tmsg: {:?}\n----------",
-                        &decoded.msg, &decoded.token, &decoded.src, &decoded.dst, &decoded.msg
+                if sender.send(wrapped_req).is_err() {
+                    eprintln!(
+                        "[FAILED] Rpc::dispatch, Request --> Receiver is dead, closing channel."
                     );
+                    return false;
                 }
+            }
+            Message::Response(res) => {
+                self.handle_response(decoded.token, res);
+            }
+        }
 
-                match decoded.msg {
-                    Message::Abort => {
-                        break;
+        true
+    }
+
+    fn allow(&self, src_addr: SocketAddr, kind: RequestKind) -> bool {
+        let allowed = self
+            .limiter
+            .lock()
+            .expect("[FAILED] Rpc::allow --> Failed to acquire mutex on Limiter")
+            .allow(src_addr.ip(), kind);
+
+        if !allowed {
+            let count = RpcStats::incr(&self.stats.rate_limited);
+            if RpcStats::should_log(count) {
+                eprintln!(
+                    "[WARNING] Rpc::allow --> Rate limiting {:?} messages from {} ({} dropped so far)",
+                    kind, src_addr, count
+                );
+            }
+        }
+
+        allowed
+    }
+
+    // serves the outbox: peers send the 32 bytes token of the message they were pointed at
+    // and get back its length (u64 BE) followed by the encoded message
+    fn serve_streams(rpc: Rpc) {
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = match rpc.listener.accept().await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        let count = RpcStats::incr(&rpc.stats.stream_errors);
+                        if RpcStats::should_log(count) {
+                            eprintln!(
+                                "[WARNING] Rpc::serve_streams --> Failed to accept connection ({} so far): {}",
+                                count, e
+                            );
+                        }
+                        continue;
                     }
-                    Message::Request(req) => {
-                        let wrapped_req = ReqWrapper {
-                            token: decoded.token,
-                            src: decoded.src,
-                            payload: req,
+                };
+
+                let outbox = rpc.outbox.clone();
+                let stats = rpc.stats.clone();
+                tokio::spawn(async move {
+                    let served = async {
+                        let mut token = [0u8; KEY_LEN];
+                        stream.read_exact(&mut token).await?;
+
+                        let encoded = outbox
+                            .lock()
+                            .expect(
+                                "[FAILED] Rpc::serve_streams --> Failed to acquire mutex on Outbox",
+                            )
+                            .take(&Key(token));
+                        let Some(encoded) = encoded else {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::NotFound,
+                                "unknown token",
+                            ));
                         };
 
-                        if let Err(_) = sender.send(wrapped_req) {
-                            eprintln!("[FAILED] Rpc::open, Request --> Receiver is dead, closing channel.");
-                            break;
+                        stream
+                            .write_all(&(encoded.len() as u64).to_be_bytes())
+                            .await?;
+                        stream.write_all(&encoded).await?;
+                        stream.shutdown().await
+                    };
+
+                    match tokio::time::timeout(Duration::from_millis(TIMEOUT), served).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            let count = RpcStats::incr(&stats.stream_errors);
+                            if RpcStats::should_log(count) {
+                                eprintln!(
+                                    "[WARNING] Rpc::serve_streams --> Failed to serve {} ({} so far): {}",
+                                    peer, count, e
+                                );
+                            }
+                        }
+                        Err(_) => {
+                            let count = RpcStats::incr(&stats.stream_errors);
+                            if RpcStats::should_log(count) {
+                                eprintln!(
+                                    "[WARNING] Rpc::serve_streams --> Timed out serving {} ({} so far)",
+                                    peer, count
+                                );
+                            }
                         }
                     }
-                    Message::Response(res) => {
-                        rpc.clone().handle_response(decoded.token, res);
-                    }
-                }
+                });
             }
         });
     }
 
-    pub fn send_msg(&self, msg: &RpcMessage) {
-        let encoded = serde_json::to_string(msg)
-            .expect("[FAILED] Rpc::send_msg --> Unable to serialize message");
-        self.socket
-            .send_to(&encoded.as_bytes(), &msg.dst)
-            .expect("[FAILED] Rpc::send_msg --> Error while sending message to specified address");
+    // fetches a message announced by a Stream datagram and dispatches it as if it came over udp
+    async fn fetch_stream(
+        &self,
+        token: Key,
+        src_addr: SocketAddr,
+        port: u16,
+        size: u64,
+        sender: mpsc::UnboundedSender<ReqWrapper>,
+    ) {
+        if size > MAX_STREAM_SIZE as u64 {
+            let count = RpcStats::incr(&self.stats.rejected);
+            if RpcStats::should_log(count) {
+                eprintln!(
+                    "[WARNING] Rpc::fetch_stream --> Rejecting {} bytes message from {} ({} so far)",
+                    size, src_addr, count
+                );
+            }
+            return;
+        }
+        // held until the message is dispatched, it counts against the budget as long as it is in memory
+        let Ok(_permit) = self.fetches.clone().try_acquire_many_owned(size as u32) else {
+            let count = RpcStats::incr(&self.stats.over_budget);
+            if RpcStats::should_log(count) {
+                eprintln!(
+                    "[WARNING] Rpc::fetch_stream --> Fetch budget exhausted, dropping {} bytes message from {} ({} so far)",
+                    size, src_addr, count
+                );
+            }
+            return;
+        };
+
+        let stream_addr = SocketAddr::new(src_addr.ip(), port);
+        let fetched = async {
+            let mut stream = TcpStream::connect(stream_addr).await?;
+            stream.write_all(&token.0).await?;
+
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len).await?;
+            let len = u64::from_be_bytes(len);
+            if len != size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("announced {} bytes but got {}", size, len),
+                ));
+            }
+
+            // the buffer grows with what the peer actually sends rather than with what it announced
+            let mut encoded = Vec::new();
+            (&mut stream).take(len).read_to_end(&mut encoded).await?;
+            if encoded.len() as u64 != len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("expected {} bytes but got {}", len, encoded.len()),
+                ));
+            }
+            Ok(encoded)
+        };
+
+        let encoded = match tokio::time::timeout(Duration::from_millis(TIMEOUT), fetched).await {
+            Ok(Ok(encoded)) => encoded,
+            Ok(Err(e)) => {
+                let count = RpcStats::incr(&self.stats.stream_errors);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::fetch_stream --> Failed to fetch message from {} ({} so far): {}",
+                        stream_addr, count, e
+                    );
+                }
+                return;
+            }
+            Err(_) => {
+                let count = RpcStats::incr(&self.stats.stream_errors);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::fetch_stream --> Timed out fetching message from {} ({} so far)",
+                        stream_addr, count
+                    );
+                }
+                return;
+            }
+        };
+
+        match Rpc::decode(&encoded) {
+            // the token is checked so a peer can only stream the message it announced
+            Ok((decoded, format)) if decoded.token == token => {
+                RpcStats::incr(&self.stats.streamed_in);
+                self.dispatch(decoded, format, src_addr, &sender);
+            }
+            Ok(_) => {
+                let count = RpcStats::incr(&self.stats.rejected);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::fetch_stream --> Token mismatch in message from {} ({} so far)",
+                        stream_addr, count
+                    );
+                }
+            }
+            Err(e) => {
+                let count = RpcStats::incr(&self.stats.malformed);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::fetch_stream --> Dropping malformed message from {} ({} so far): {}",
+                        stream_addr, count, e
+                    );
+                }
+            }
+        }
     }
 
-    pub fn handle_response(self, token: Key, res: Response) {
-        thread::spawn(move || {
-            let mut pending = self
-                .pending
-                .lock()
-                .expect("[FAILED] Rpc::handle_response --> Failed to acquire lock on Pending");
+    // messages too large for a datagram are parked in the outbox and announced with a Stream datagram
+    pub async fn send_msg(&self, msg: &RpcMessage, format: WireFormat) {
+        let mut encoded = Rpc::encode(msg, format);
 
-            let tmp = match pending.get(&token) {
-                Some(sender) => sender.send(Some(res)),
-                None => {
+        if encoded.len() > BUF_SIZE {
+            let port = match self.listener.local_addr() {
+                Ok(addr) => addr.port(),
+                Err(e) => {
                     eprintln!(
-                        "[WARNING] Rpc::handle_response --> Unsolicited response received, ignoring..."
+                        "[FAILED] Rpc::send_msg --> Stream listener has no address: {}",
+                        e
                     );
                     return;
                 }
             };
 
-            if let Ok(_) = tmp {
-                pending.remove(&token);
+            let size = encoded.len() as u64;
+            let parked = self
+                .outbox
+                .lock()
+                .expect("[FAILED] Rpc::send_msg --> Failed to acquire mutex on Outbox")
+                .park(msg.token.clone(), encoded);
+            if !parked {
+                let count = RpcStats::incr(&self.stats.over_budget);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::send_msg --> Outbox is full, dropping {} bytes message to {} ({} so far)",
+                        size, msg.dst, count
+                    );
+                }
+                return;
             }
-        });
+            RpcStats::incr(&self.stats.streamed_out);
+
+            // the peer has as long as a request lasts to fetch it
+            let outbox = self.outbox.clone();
+            let token = msg.token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(TIMEOUT)).await;
+                outbox
+                    .lock()
+                    .expect("[FAILED] Rpc::send_msg --> Failed to acquire mutex on Outbox")
+                    .take(&token);
+            });
+
+            let announcement = RpcMessage {
+                token: msg.token.clone(),
+                src: msg.src.clone(),
+                dst: msg.dst.clone(),
+                msg: Message::Stream(port, size),
+                enr: None,
+            };
+            encoded = Rpc::encode(&announcement, format);
+        }
+
+        if let Err(e) = self.socket.send_to(&encoded, &msg.dst).await {
+            eprintln!(
+                "[FAILED] Rpc::send_msg --> Error while sending message to {}: {}",
+                msg.dst, e
+            );
+        }
     }
 
-    pub fn make_request(&self, req: Request, dst: Node) -> mpsc::Receiver<Option<Response>> {
-        let (sender, receiver) = mpsc::channel();
-        let mut pending = self
+    pub fn handle_response(&self, token: Key, res: Response) {
+        let sender = self
             .pending
             .lock()
-            .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending");
+            .expect("[FAILED] Rpc::handle_response --> Failed to acquire lock on Pending")
+            .remove(&token);
+
+        match sender {
+            // the requester may have timed out in the meantime, nothing left to do then
+            Some(sender) => {
+                let _ = sender.send(res);
+            }
+            None => {
+                let count = RpcStats::incr(&self.stats.unsolicited);
+                if RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Rpc::handle_response --> Unsolicited response received, ignoring... ({} so far)",
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn local_enr(&self) -> Enr {
+        self.local_enr
+            .read()
+            .expect("[FAILED] Rpc::local_enr --> Failed to acquire lock on Local ENR")
+            .clone()
+    }
+
+    // only newer records of our own node id are taken
+    pub fn set_local_enr(&self, enr: Enr) -> bool {
+        let mut local_enr = self
+            .local_enr
+            .write()
+            .expect("[FAILED] Rpc::set_local_enr --> Failed to acquire lock on Local ENR");
+        if enr.node_id() != local_enr.node_id() || enr.seq() <= local_enr.seq() {
+            return false;
+        }
+
+        *local_enr = enr;
+        true
+    }
+
+    pub async fn make_request(&self, req: Request, dst: Node) -> Option<Response> {
+        let (sender, receiver) = oneshot::channel();
 
         let token = Key::new(format!(
             "{}:{}:{:?}",
@@ -164,29 +731,121 @@ impl Rpc {
             dst.get_info(),
             std::time::SystemTime::now()
         ));
-        pending.insert(token.clone(), sender.clone());
+        self.pending
+            .lock()
+            .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending")
+            .insert(token.clone(), sender);
 
         let msg = RpcMessage {
             token: token.clone(),
             src: self.node.get_addr(),
             dst: dst.get_addr(),
             msg: Message::Request(req),
+            enr: Some(self.local_enr()),
         };
 
-        self.send_msg(&msg);
+        self.send_msg(&msg, self.format).await;
 
-        let rpc = self.clone();
-        thread::spawn(move || {
-            thread::sleep(std::time::Duration::from_millis(TIMEOUT));
-            if let Ok(_) = sender.send(None) {
-                let mut pending = rpc
-                    .pending
+        match tokio::time::timeout(Duration::from_millis(TIMEOUT), receiver).await {
+            Ok(Ok(res)) => Some(res),
+            _ => {
+                self.pending
                     .lock()
-                    .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending");
-                pending.remove(&token);
+                    .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending")
+                    .remove(&token);
+                None
             }
-        });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::routing::FindValueResult;
+    use super::super::storage::Version;
+    use super::super::testutil::spawn_node;
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_values_go_through_the_stream() {
+        let b = spawn_node(47201, None).await;
+        let a = spawn_node(47202, Some(b.node.clone())).await;
 
-        receiver
+        let value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let version = Version::new(1, a.node.id.clone());
+        assert!(
+            a.store(
+                b.node.clone(),
+                "big".to_string(),
+                value.clone(),
+                3600,
+                version,
+                None
+            )
+            .await
+        );
+        assert!(a.rpc.stats.streamed_out.load(Ordering::Relaxed) >= 1);
+        assert!(b.rpc.stats.streamed_in.load(Ordering::Relaxed) >= 1);
+
+        match a.find_value(b.node.clone(), "big".to_string()).await {
+            Some(FindValueResult::Value(found, ..)) => assert_eq!(found, value),
+            other => panic!("unexpected lookup result: {:?}", other.is_some()),
+        }
+        assert!(b.rpc.stats.streamed_out.load(Ordering::Relaxed) >= 1);
+        assert!(a.rpc.stats.streamed_in.load(Ordering::Relaxed) >= 1);
+        assert_eq!(a.rpc.stats.stream_errors.load(Ordering::Relaxed), 0);
+        assert_eq!(b.rpc.stats.stream_errors.load(Ordering::Relaxed), 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn several_large_values_in_both_directions() {
+        let b = spawn_node(47211, None).await;
+        let a = spawn_node(47212, Some(b.node.clone())).await;
+
+        for (i, size) in [2, 3, 8].into_iter().enumerate() {
+            let value = vec![i as u8 + 1; size * 1024 * 1024];
+            let key = format!("big-{}", i);
+            let (src, dst) = if i % 2 == 0 { (&a, &b) } else { (&b, &a) };
+
+            let version = Version::new(1, src.node.id.clone());
+            assert!(
+                src.store(
+                    dst.node.clone(),
+                    key.clone(),
+                    value.clone(),
+                    3600,
+                    version,
+                    None
+                )
+                .await
+            );
+            match src.find_value(dst.node.clone(), key).await {
+                Some(FindValueResult::Value(found, ..)) => assert!(found == value),
+                other => panic!("unexpected lookup result: {:?}", other.is_some()),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_messages_stay_on_udp() {
+        let b = spawn_node(47221, None).await;
+        let a = spawn_node(47222, Some(b.node.clone())).await;
+
+        let version = Version::new(1, a.node.id.clone());
+        assert!(
+            a.store(
+                b.node.clone(),
+                "small".to_string(),
+                b"value".to_vec(),
+                3600,
+                version,
+                None
+            )
+            .await
+        );
+        assert!(a.ping(b.node.clone()).await);
+        assert_eq!(a.rpc.stats.streamed_out.load(Ordering::Relaxed), 0);
+        assert_eq!(b.rpc.stats.streamed_out.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,19 +1,49 @@
 use super::key::Key;
+use crate::discovery::enr_builder::{DHT_ENR_KEY, DHT_IP_ENR_KEY};
+use discv5::Enr;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(from = "NodeRecord", into = "NodeRecord")]
 pub struct Node {
     pub ip: String,
     pub port: u16,
     pub id: Key,
+    // signed record the id is taken from, its signature is checked whenever it is decoded
+    pub enr: Enr,
+}
+
+// what goes over the wire: the id is never sent, it is derived again from the decoded enr
+// so a peer can't hand out a node under an id of its choosing
+#[derive(Serialize, Deserialize)]
+struct NodeRecord {
+    ip: String,
+    port: u16,
+    enr: Enr,
+}
+
+impl From<NodeRecord> for Node {
+    fn from(record: NodeRecord) -> Self {
+        Node::new(record.enr, record.ip, record.port)
+    }
+}
+
+impl From<Node> for NodeRecord {
+    fn from(node: Node) -> Self {
+        NodeRecord {
+            ip: node.ip,
+            port: node.port,
+            enr: node.enr,
+        }
+    }
 }
 
 impl Node {
-    pub fn new(ip: String, port: u16) -> Self {
-        let addr = format!("{}:{}", ip, port);
-        let id = Key::new(addr);
+    pub fn new(enr: Enr, ip: String, port: u16) -> Self {
+        let id = Key::from_node_id(&enr.node_id());
 
-        Node { ip, port, id }
+        Node { ip, port, id, enr }
     }
     
     pub fn get_info(&self) -> String {
@@ -26,4 +56,22 @@ impl Node {
     pub fn get_addr(&self) -> String {
         format!("{}:{}", self.ip, self.port)
     }
-}
\ No newline at end of file
+
+    // whether the enr advertises the DHT ip and port the node is reached at. ENRs are public,
+    // anyone could otherwise pair a known one with their own address and take over its contact
+    pub fn advertises_address(&self) -> bool {
+        let ip_matches = match (
+            self.ip.parse::<IpAddr>(),
+            self.enr.get_decodable::<IpAddr>(DHT_IP_ENR_KEY),
+        ) {
+            (Ok(ip), Some(Ok(advertised))) => ip.to_canonical() == advertised.to_canonical(),
+            _ => false,
+        };
+        let port_matches = matches!(
+            self.enr.get_decodable::<u16>(DHT_ENR_KEY),
+            Some(Ok(port)) if port == self.port
+        );
+
+        ip_matches && port_matches
+    }
+}
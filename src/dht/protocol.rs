@@ -1,162 +1,470 @@
 use super::network;
 use super::node::Node;
+use super::chunks;
+use super::lookup;
+use super::content;
+use super::ratelimit;
 use super::routing;
+use super::signed::{self, RecordSignature};
+use super::storage::{Record, Storage, Version};
 use super::utils;
-use super::config::{K_PARAM, ALPHA, };
-use crossbeam_channel;
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::sync::mpsc;
+use discv5::enr::EnrKey;
+use discv5::Enr;
+use super::config::{
+    K_PARAM, ALPHA, CHUNK_PARALLELISM, DISJOINT_PATHS, MAX_CONCURRENT_HANDLERS, STORE_QUOTA_BYTES, STORE_QUOTA_RECORDS, CHUNK_SIZE, REFRESH_CHECK_INTERVAL, REFRESH_INTERVAL, REPLICATE_INTERVAL, REPUBLISH_INTERVAL,
+    MAX_SEQ, READ_QUORUM, RECORD_TTL, REPLICATION_FACTOR, SCHEDULE_JITTER, SWEEP_INTERVAL, SYNC_INTERVAL, TOMBSTONE_TTL, VALUE_REPLICAS,
+};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
 
 #[derive(Debug, Clone)]
 pub struct Protocol {
     pub routes: Arc<Mutex<routing::RoutingTable>>,
-    pub store: Arc<Mutex<HashMap<String, String>>>,
+    pub store: Arc<Mutex<Box<dyn Storage>>>,
     pub rpc: Arc<network::Rpc>,
     pub node: Node,
+    // signs the records published under our own key, None when we don't publish any
+    signer: Option<signed::Signer>,
 }
 
-impl Protocol {
-    pub fn new(ip: String, port: u16, bootstrap: Option<Node>) -> Self {
-        let node = Node::new(ip, port);
+// replicas that acknowledged a write or answered a read, out of the number required
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quorum {
+    pub acks: usize,
+    pub required: usize,
+}
 
-        // channel used for a 2-way communication with the Routing Table module
-        let (rt_channel_sender, rt_channel_receiver) = crossbeam_channel::unbounded();
+impl Quorum {
+    pub fn is_met(&self) -> bool {
+        self.acks >= self.required
+    }
+}
 
-        let routes = routing::RoutingTable::new(
-            node.clone(),
-            bootstrap,
-            rt_channel_sender.clone(),
-            rt_channel_receiver.clone(),
-        );
+// outcome of a record pushed by another node
+enum Acceptance {
+    Stored,
+    // we hold a newer record, which is sent back
+    Outdated(Record),
+    OverQuota,
+}
+
+impl Protocol {
+    pub async fn new(
+        node: Node,
+        bind_addr: SocketAddr,
+        bootstrap: Option<Node>,
+        store: Box<dyn Storage>,
+        format: network::WireFormat,
+        limits: routing::IpLimits,
+        signer: Option<signed::Signer>,
+    ) -> std::io::Result<Self> {
+        let routes = routing::RoutingTable::new(node.clone(), bootstrap, limits);
 
         // 1-way channel to communicate with the Network module
-        let (rpc_channel_sender, rpc_channel_receiver) = mpsc::channel();
+        let (rpc_channel_sender, rpc_channel_receiver) = mpsc::unbounded_channel();
 
-        let rpc = network::Rpc::new(node.clone());
+        let rpc = network::Rpc::new(node.clone(), bind_addr, format).await?;
         network::Rpc::open(rpc.clone(), rpc_channel_sender);
 
         let protocol = Self {
             routes: Arc::new(Mutex::new(routes)),
-            store: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(Mutex::new(store)),
             rpc: Arc::new(rpc),
             node: node.clone(),
+            signer,
         };
 
         protocol.clone().requests_handler(rpc_channel_receiver);
-        protocol
-            .clone()
-            .rt_forwarder(rt_channel_sender, rt_channel_receiver);
 
         // performing node lookup on ourselves
-        protocol.nodes_lookup(&node.id);
+        protocol.nodes_lookup(&node.id).await;
 
-        // republishing <key, value> pairs every hour
+        // evicting expired <key, value> pairs
         let protocol_clone = protocol.clone();
-        std::thread::spawn(move || {
-            std::thread::sleep(std::time::Duration::from_secs(60 * 60));
-            protocol_clone.republish();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL)).await;
+                protocol_clone.sweep();
+            }
         });
-        protocol
+
+        // writing stored records through to disk in batches, a fsync per record would stall the runtime
+        let protocol_clone = protocol.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(SYNC_INTERVAL)).await;
+                protocol_clone.sync().await;
+            }
+        });
+
+        // refreshing buckets that were not looked up for an hour
+        let protocol_clone = protocol.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(REFRESH_CHECK_INTERVAL)).await;
+                protocol_clone.refresh().await;
+            }
+        });
+
+        // replicating <key, value> pairs every hour, the jitter keeps replicas from firing together
+        let protocol_clone = protocol.clone();
+        tokio::spawn(async move {
+            loop {
+                let jitter = rand::random::<u64>() % (SCHEDULE_JITTER + 1);
+                tokio::time::sleep(Duration::from_secs(REPLICATE_INTERVAL + jitter)).await;
+                protocol_clone.republish().await;
+            }
+        });
+        Ok(protocol)
     }
 
-    fn republish(&self) {
-        let st = self
+    async fn republish(&self) {
+        // the guard can't be held across await points, so we work on a snapshot of the store
+        let entries = self
             .store
             .lock()
-            .expect("[FAILED] Protocol::republish --> Failed to acquire mutex on Store");
-        for (key, value) in &*st {
-            self.put(key.to_string(), value.to_string());
+            .expect("[FAILED] Protocol::republish --> Failed to acquire mutex on Store")
+            .entries();
+        let now = utils::now_secs();
+
+        for (key, mut record) in entries {
+            if record.is_expired() {
+                continue;
+            }
+
+            // due every 24h, or sooner when the record would expire before the next pass
+            let republish_due = now.saturating_sub(record.published_at) >= REPUBLISH_INTERVAL
+                || record.expires <= now.saturating_add(REPLICATE_INTERVAL + SCHEDULE_JITTER);
+
+            match record.publish_ttl {
+                // only the original publisher extends the lifetime of a record
+                Some(ttl) if republish_due => {
+                    record.expires = now.saturating_add(ttl);
+                    record.published_at = now;
+                    // the signature covers the expiry, so signed records are signed again
+                    if record.signature.is_some() {
+                        let Some(signature) = self
+                            .signer
+                            .as_ref()
+                            .and_then(|signer| signed::sign(signer.key(), &key, &record))
+                        else {
+                            eprintln!(
+                                "[WARNING] Protocol::republish --> Unable to sign key {} again, it is left to expire",
+                                key
+                            );
+                            continue;
+                        };
+                        record.signature = Some(signature);
+                    }
+                    self.insert_local(&key, record.clone());
+                    self.republish_chunks(&record).await;
+                }
+                // another replica stored it on us within the hour, so it already reached the k closest
+                _ if now.saturating_sub(record.received_at) < REPLICATE_INTERVAL => continue,
+                _ => {}
+            }
+
+            self.replicate(key, record).await;
         }
     }
 
-    // forwards upcoming requests (only Pings at the moment) from the Routing table
-    fn rt_forwarder(
-        self,
-        sender: crossbeam_channel::Sender<utils::ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<utils::ChannelPayload>,
-    ) {
-        std::thread::spawn(move || {
-            for req in receiver.iter() {
-                let protocol = self.clone();
-                let sender_clone = sender.clone();
-
-                std::thread::spawn(move || match req {
-                    utils::ChannelPayload::Request(payload) => match payload.0 {
-                        network::Request::Ping => {
-                            let success = protocol.ping(payload.1);
-                            if success {
-                                if let Err(_) = sender_clone
-                                    .send(utils::ChannelPayload::Response(network::Response::Ping))
-                                {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            } else {
-                                if let Err(_) = sender_clone.send(utils::ChannelPayload::NoData) {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            }
-                        }
-                        _ => {
-                            unimplemented!();
+    // chunks live as long as their manifest, they are pushed again with its new lifetime. A chunk
+    // we no longer hold (evicted, or dropped along with another manifest sharing it) is fetched back
+    async fn republish_chunks(&self, manifest: &Record) {
+        let chunks: Vec<(String, Option<Record>)> = {
+            let store = self
+                .store
+                .lock()
+                .expect("[FAILED] Protocol::republish_chunks --> Failed to acquire mutex on Store");
+            chunk_keys(store.as_ref(), &manifest.value)
+                .into_iter()
+                .map(|k| {
+                    let chunk = store.get(&k).filter(|chunk| !chunk.is_expired());
+                    (k, chunk)
+                })
+                .collect()
+        };
+
+        let mut joins = JoinSet::new();
+        for (k, chunk) in chunks {
+            if joins.len() >= CHUNK_PARALLELISM {
+                joins.join_next().await;
+            }
+
+            let protocol = self.clone();
+            let expires = manifest.expires;
+            joins.spawn(async move {
+                let mut chunk = match chunk {
+                    Some(chunk) => chunk,
+                    None => match protocol.fetch(k.clone(), READ_QUORUM).await.0 {
+                        Some(v) => Record::new(v, 0, Version::default()),
+                        None => {
+                            eprintln!(
+                                "[WARNING] Protocol::republish_chunks --> Chunk {} is lost, it can't be republished",
+                                k
+                            );
+                            return;
                         }
                     },
-                    utils::ChannelPayload::Response(_) => {
-                        eprintln!("[FAILED] Protocol::rt_forwarder --> Received a Response instead of a Request")
-                    }
-                    utils::ChannelPayload::NoData => {
-                        eprintln!("[FAILED] Protocol::rt_forwarder --> Received a NoData instead of a Request")
-                    }
-                });
+                };
+                chunk.expires = expires;
+                protocol.insert_local(&k, chunk.clone());
+                protocol.replicate(k, chunk).await;
+            });
+        }
+        while let Some(joined) = joins.join_next().await {
+            joined.expect(
+                "[FAILED] Protocol::republish_chunks --> Failed to join task while storing chunks",
+            );
+        }
+    }
+
+    fn insert_local(&self, key: &str, record: Record) {
+        let mut store = self
+            .store
+            .lock()
+            .expect("[FAILED] Protocol::insert_local --> Failed to acquire mutex on Store");
+        if let Some(old) = store.get(key) {
+            release_chunks(store.as_mut(), &old, &record);
+        }
+        if let Err(e) = store.insert(key.to_string(), record) {
+            eprintln!(
+                "[FAILED] Protocol::insert_local --> Unable to persist value: {}",
+                e
+            );
+        }
+    }
+
+    // pushes the record with its remaining lifetime to the closest nodes and returns how many of
+    // them acknowledged it, our own copy counts when we are one of them
+    async fn replicate(&self, k: String, record: Record) -> usize {
+        let candidates = self.nodes_lookup(&super::key::Key::new(k.clone())).await;
+        let mut acks = 0;
+        let mut joins = JoinSet::new();
+
+        for routing::NodeAndDistance(node, _) in candidates.into_iter().take(REPLICATION_FACTOR) {
+            // our own copy is already up to date
+            if node.id == self.node.id {
+                acks += 1;
+                continue;
             }
-        });
+
+            let protocol_clone = self.clone();
+            let k_clone = k.clone();
+            let record_clone = record.clone();
+
+            joins.spawn(async move { protocol_clone.push(node, k_clone, &record_clone).await });
+        }
+
+        while let Some(joined) = joins.join_next().await {
+            let stored = joined.expect(
+                "[FAILED] Protocol::replicate --> Failed to join task while storing replicas",
+            );
+            if stored {
+                acks += 1;
+            }
+        }
+
+        acks
+    }
+
+    // sends a value as a Store and a tombstone as a Delete
+    async fn push(&self, dst: Node, k: String, record: &Record) -> bool {
+        if record.deleted {
+            self.delete(
+                dst,
+                k,
+                record.ttl(),
+                record.version.clone(),
+                record.signature.clone(),
+            )
+            .await
+        } else {
+            self.store(
+                dst,
+                k,
+                record.value.clone(),
+                record.ttl(),
+                record.version.clone(),
+                record.signature.clone(),
+            )
+            .await
+        }
+    }
+
+    async fn refresh(&self) {
+        let targets: Vec<super::key::Key> = {
+            let routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::refresh --> Failed to acquire mutex on Routes");
+            routes
+                .stale_buckets(REFRESH_INTERVAL)
+                .into_iter()
+                .map(|idx| routes.random_key_in_bucket(idx))
+                .collect()
+        };
+
+        // the lookups touch the buckets, so each one is refreshed at most once per interval
+        for target in targets {
+            self.nodes_lookup(&target).await;
+        }
+    }
+
+    fn sweep(&self) {
+        let mut st = self
+            .store
+            .lock()
+            .expect("[FAILED] Protocol::sweep --> Failed to acquire mutex on Store");
+        if let Err(e) = st.evict_expired() {
+            eprintln!(
+                "[FAILED] Protocol::sweep --> Unable to evict expired values: {}",
+                e
+            );
+        }
+    }
+
+    // disk I/O runs on the blocking pool so it never holds up the runtime threads
+    async fn sync(&self) {
+        let store = self.store.clone();
+        let synced = tokio::task::spawn_blocking(move || {
+            store
+                .lock()
+                .expect("[FAILED] Protocol::sync --> Failed to acquire mutex on Store")
+                .sync()
+        })
+        .await
+        .expect("[FAILED] Protocol::sync --> Failed to join task while syncing the store");
+
+        if let Err(e) = synced {
+            eprintln!(
+                "[FAILED] Protocol::sync --> Unable to write records to disk: {}",
+                e
+            );
+        }
+    }
+
+    // adds or refreshes a contact, pinging the least recently seen node of a full bucket
+    // in the background so the routing table is never locked while waiting on the network
+    fn update_routes(&self, node: Node) {
+        let res = self
+            .routes
+            .lock()
+            .expect("[FAILED] Protocol::update_routes --> Failed to acquire mutex on Routes")
+            .update(node);
+
+        if let routing::UpdateResult::CheckLiveness(lrs) = res {
+            let protocol = self.clone();
+            tokio::spawn(async move {
+                let res =
+                    utils::make_req_get_res(&protocol.rpc, network::Request::Ping, lrs.clone())
+                        .await;
+                let alive = matches!(res, Some(network::Response::Ping));
+
+                protocol
+                    .routes
+                    .lock()
+                    .expect(
+                        "[FAILED] Protocol::update_routes --> Failed to acquire mutex on Routes",
+                    )
+                    .resolve_check(&lrs, alive);
+            });
+        }
+    }
+
+    // whether the routing table has room for a contact from the node's subnet
+    pub fn admits(&self, node: &Node) -> bool {
+        self.routes
+            .lock()
+            .expect("[FAILED] Protocol::admits --> Failed to acquire mutex on Routes")
+            .admits(node)
+    }
+
+    // a single missed reply only counts against the contact, it is dropped after MAX_FAILURES
+    fn record_failure(&self, node: &Node) -> bool {
+        self.routes
+            .lock()
+            .expect("[FAILED] Protocol::record_failure --> Failed to acquire mutex on Routes")
+            .record_failure(node)
     }
 
-    // handles requests by crafting responses and sending them
-    fn requests_handler(self, receiver: mpsc::Receiver<network::ReqWrapper>) {
-        std::thread::spawn(move || {
-            for req in receiver.iter() {
+    // handles requests by crafting responses and sending them, at most MAX_CONCURRENT_HANDLERS
+    // at a time so a flood can't pile up tasks
+    fn requests_handler(self, mut receiver: mpsc::UnboundedReceiver<network::ReqWrapper>) {
+        let handlers = Arc::new(Semaphore::new(MAX_CONCURRENT_HANDLERS));
+
+        tokio::spawn(async move {
+            while let Some(req) = receiver.recv().await {
+                let Ok(permit) = handlers.clone().try_acquire_owned() else {
+                    let count = network::RpcStats::incr(&self.rpc.stats.busy);
+                    if network::RpcStats::should_log(count) {
+                        eprintln!(
+                            "[WARNING] Protocol::requests_handler --> Too many requests in progress, dropping request from {} ({} dropped so far)",
+                            req.src, count
+                        );
+                    }
+                    continue;
+                };
                 let protocol = self.clone();
 
-                std::thread::spawn(move || {
-                    let res = protocol.craft_res(req);
-                    protocol.reply(res);
+                tokio::spawn(async move {
+                    if let Some(res) = protocol.craft_res(req).await {
+                        protocol.reply(res).await;
+                    }
+                    drop(permit);
                 });
             }
         });
     }
 
-    fn craft_res(&self, req: network::ReqWrapper) -> (network::Response, network::ReqWrapper) {
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes");
-
+    async fn craft_res(
+        &self,
+        req: network::ReqWrapper,
+    ) -> Option<(network::Response, network::ReqWrapper)> {
         // must craft node object because ReqWrapper contains only the src string addr
-        let split = req.src.split(":");
-        let parsed: Vec<&str> = split.collect();
-
-        let src_node = Node::new(
-            parsed[0].to_string(),
-            parsed[1]
-                .parse::<u16>()
-                .expect("[FAILED] Protocol::craft_res --> Failed to parse Node port from address"),
-        );
-        routes.update(src_node);
-        drop(routes);
+        let src_addr = match req.src.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => {
+                let count = network::RpcStats::incr(&self.rpc.stats.rejected);
+                if network::RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Protocol::craft_res --> Rejecting request with invalid source address {} ({} so far)",
+                        req.src, count
+                    );
+                }
+                return None;
+            }
+        };
 
-        match req.payload {
+        // the sender can only be added to our table when we know its id, requests without a record are still served
+        if let Some(enr) = req.enr.clone() {
+            let src_node = Node::new(enr, src_addr.ip().to_string(), src_addr.port());
+            self.update_routes(src_node);
+        }
+
+        let res = match req.payload {
             network::Request::Ping => (network::Response::Ping, req),
-            network::Request::Store(ref k, ref v) => {
+            network::Request::Store(ref k, ref v, ttl, ref version, ref signature) => {
                 // ref is used to borrow k and v, which are the contents of req
+                let record = Record {
+                    signature: signature.clone(),
+                    ..Record::new(v.clone(), ttl, version.clone())
+                }
+                .cap_lifetime();
 
-                let mut store = self
-                    .store
-                    .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-                store.insert(k.to_string(), v.to_string());
+                (self.receive_record(k, record, &req)?, req)
+            }
+            network::Request::Delete(ref k, ttl, ref version, ref signature) => {
+                let record = Record {
+                    signature: signature.clone(),
+                    ..Record::tombstone(ttl, version.clone())
+                }
+                .cap_lifetime();
 
-                (network::Response::Ping, req)
+                (self.receive_record(k, record, &req)?, req)
             }
             network::Request::FindNode(ref id) => {
                 let routes = self
@@ -170,17 +478,18 @@ impl Protocol {
             }
             network::Request::FindValue(ref k) => {
                 let key = super::key::Key::new(k.to_string());
-                let store = self
+                // expired values are never served, even if the sweeper didn't evict them yet
+                let val = self
                     .store
                     .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-
-                let val = store.get(k);
+                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store")
+                    .get(k)
+                    .filter(|record| !record.is_expired());
 
                 match val {
-                    Some(v) => (
-                        network::Response::FindValue(routing::FindValueResult::Value(
-                            v.to_string(),
+                    Some(record) => (
+                        network::Response::FindValue(routing::FindValueResult::from_record(
+                            &record,
                         )),
                         req,
                     ),
@@ -197,269 +506,1090 @@ impl Protocol {
                     }
                 }
             }
+        };
+
+        Some(res)
+    }
+
+    // stores a record pushed by another node once verified, None when it is dropped without a reply
+    // because it doesn't match its key
+    fn receive_record(
+        &self,
+        k: &str,
+        record: Record,
+        req: &network::ReqWrapper,
+    ) -> Option<network::Response> {
+        if !self.verify_record(k, &record, &req.src) {
+            return None;
+        }
+
+        match self.accept_record(k, record, req.enr.as_ref(), &req.src) {
+            Acceptance::Stored => Some(network::Response::Ping),
+            Acceptance::Outdated(newer) => Some(network::Response::Outdated(
+                routing::FindValueResult::from_record(&newer),
+            )),
+            Acceptance::OverQuota => Some(network::Response::Refused),
         }
     }
 
-    fn reply(&self, packet_details: (network::Response, network::ReqWrapper)) {
+    // keys owned by a public key only take records signed by their owner, content keys only take
+    // the value they are the hash of, and no key takes a version that can't be outranked
+    fn verify_record(&self, k: &str, record: &Record, src: &str) -> bool {
+        if record.version.is_plausible() && signed::verify(k, record) && content::verify(k, record) {
+            return true;
+        }
+
+        let count = network::RpcStats::incr(&self.rpc.stats.rejected);
+        if network::RpcStats::should_log(count) {
+            eprintln!(
+                "[WARNING] Protocol::verify_record --> Rejecting record not matching key {} from {} ({} so far)",
+                k, src, count
+            );
+        }
+        false
+    }
+
+    // stores a record pushed by another node unless the one we hold is newer, in which case ours
+    // is handed back. An outdated record carrying the value we hold is as good as stored
+    fn accept_record(
+        &self,
+        k: &str,
+        mut record: Record,
+        sender: Option<&Enr>,
+        src: &str,
+    ) -> Acceptance {
+        if !sender.is_some_and(|enr| enr.node_id() == self.node.enr.node_id()) {
+            record.received_at = utils::now_secs();
+        }
+
+        let mut store = self
+            .store
+            .lock()
+            .expect("[FAILED] Protocol::accept_record --> Failed to acquire mutex on Store");
+        let old = store.get(k);
+
+        if let Some(old) = old.as_ref().filter(|old| !old.is_expired()) {
+            if old.supersedes(&record) {
+                if old.deleted == record.deleted && old.value == record.value {
+                    return Acceptance::Stored;
+                }
+                let count = network::RpcStats::incr(&self.rpc.stats.outdated);
+                if network::RpcStats::should_log(count) {
+                    eprintln!(
+                        "[WARNING] Protocol::accept_record --> Ignoring outdated value of key {} ({} so far)",
+                        k, count
+                    );
+                }
+                return Acceptance::Outdated(old.clone());
+            }
+
+            if old.version == record.version {
+                // the original publisher keeps its republishing duty when a replica pushes the record back,
+                // and the record stays charged to whoever first stored it
+                record.publish_ttl = old.publish_ttl;
+                record.published_at = old.published_at;
+                record.charged_to = old.charged_to.clone();
+                // a replayed copy can't cut the lifetime short, the signature of the longer one is kept
+                if old.expires > record.expires {
+                    record.expires = old.expires;
+                    record.signature = old.signature.clone();
+                }
+            } else if record.deleted {
+                // the tombstone must outlive the value it shadows
+                record.expires = record.expires.max(old.expires);
+            }
+        }
+        if record.charged_to.is_none() {
+            record.charged_to = Some(quota_owner(k, src));
+        }
+        let owner = record.charged_to.clone().unwrap_or_default();
+
+        // replacing a record of the same owner only changes its size
+        let usage = store.usage(&owner);
+        let replaced = old.filter(|old| old.charged_to == record.charged_to);
+        let records = usage.records + usize::from(replaced.is_none());
+        let bytes = (usage.bytes + record.value.len() as u64)
+            .saturating_sub(replaced.map_or(0, |old| old.value.len() as u64));
+        if records > STORE_QUOTA_RECORDS || bytes > STORE_QUOTA_BYTES {
+            let count = network::RpcStats::incr(&self.rpc.stats.over_quota);
+            if network::RpcStats::should_log(count) {
+                eprintln!(
+                    "[WARNING] Protocol::accept_record --> {} is over quota, refusing key {} from {} ({} so far)",
+                    owner, k, src, count
+                );
+            }
+            return Acceptance::OverQuota;
+        }
+
+        if let Some(old) = store.get(k) {
+            release_chunks(store.as_mut(), &old, &record);
+        }
+        if let Err(e) = store.insert(k.to_string(), record) {
+            eprintln!(
+                "[FAILED] Protocol::accept_record --> Unable to persist value: {}",
+                e
+            );
+        }
+        Acceptance::Stored
+    }
+
+    async fn reply(&self, packet_details: (network::Response, network::ReqWrapper)) {
+        let format = packet_details.1.format;
         let msg = network::RpcMessage {
             token: packet_details.1.token,
             src: self.node.get_addr(),
             dst: packet_details.1.src,
             msg: network::Message::Response(packet_details.0),
+            enr: Some(self.rpc.local_enr()),
         };
 
-        self.rpc.send_msg(&msg);
+        self.rpc.send_msg(&msg, format).await;
     }
 
-    pub fn ping(&self, dst: Node) -> bool {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::Ping, dst.clone());
-
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::ping --> Failed to acquire lock on Routes");
+    pub async fn ping(&self, dst: Node) -> bool {
+        let res = utils::make_req_get_res(&self.rpc, network::Request::Ping, dst.clone()).await;
 
         if let Some(network::Response::Ping) = res {
-            routes.update(dst);
+            self.update_routes(dst);
             true
         } else {
-            eprintln!(
-                "[WARNING] Protocol::Ping --> No response, removing contact from routing table"
-            );
-            routes.remove(&dst);
+            if self.record_failure(&dst) {
+                eprintln!(
+                    "[WARNING] Protocol::Ping --> No response, removing contact from routing table"
+                );
+            }
             false
         }
     }
 
-    pub fn store(&self, dst: Node, key: String, val: String) -> bool {
-        let res =
-            utils::make_req_get_res(&self.rpc, network::Request::Store(key, val), dst.clone());
+    pub async fn store(
+        &self,
+        dst: Node,
+        key: String,
+        val: Vec<u8>,
+        ttl: u64,
+        version: Version,
+        signature: Option<RecordSignature>,
+    ) -> bool {
+        let res = utils::make_req_get_res(
+            &self.rpc,
+            network::Request::Store(key.clone(), val, ttl, version, signature),
+            dst.clone(),
+        )
+        .await;
+
+        self.write_acked(dst, &key, res)
+    }
 
-        // since we get a ping, update our routing table
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::store --> Failed to acquire mutex on Routes");
-        if let Some(network::Response::Ping) = res {
-            routes.update(dst);
-            true
-        } else {
-            routes.remove(&dst);
-            false
+    pub async fn delete(
+        &self,
+        dst: Node,
+        key: String,
+        ttl: u64,
+        version: Version,
+        signature: Option<RecordSignature>,
+    ) -> bool {
+        let res = utils::make_req_get_res(
+            &self.rpc,
+            network::Request::Delete(key.clone(), ttl, version, signature),
+            dst.clone(),
+        )
+        .await;
+
+        self.write_acked(dst, &key, res)
+    }
+
+    // whether a Store or Delete was acknowledged. A replica holding a newer record or tombstone
+    // sends it back and we take it in place of ours, so a publisher learns its value was
+    // overwritten or deleted elsewhere and stops republishing it
+    fn write_acked(&self, dst: Node, key: &str, res: Option<network::Response>) -> bool {
+        match res {
+            // since we get a ping, update our routing table
+            Some(network::Response::Ping) => {
+                self.update_routes(dst);
+                true
+            }
+            Some(network::Response::Outdated(newer)) => {
+                if let Some(newer) = newer.into_record() {
+                    if self.verify_record(key, &newer, &dst.get_addr()) {
+                        self.accept_record(key, newer, Some(&dst.enr), &dst.get_addr());
+                    }
+                }
+                self.update_routes(dst);
+                false
+            }
+            // the replica is alive but keeps no more records for us
+            Some(network::Response::Refused) => {
+                self.update_routes(dst);
+                false
+            }
+            _ => {
+                self.record_failure(&dst);
+                false
+            }
         }
     }
 
-    pub fn find_node(
+    pub async fn find_node(
         &self,
         dst: Node,
         id: super::key::Key,
     ) -> Option<Vec<routing::NodeAndDistance>> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindNode(id), dst.clone());
+        let res =
+            utils::make_req_get_res(&self.rpc, network::Request::FindNode(id), dst.clone()).await;
 
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::find_node --> Failed to acquire mutex on Routes");
         if let Some(network::Response::FindNode(entries)) = res {
-            routes.update(dst);
-            Some(entries)
+            self.update_routes(dst);
+            Some(advertised(entries))
         } else {
-            routes.remove(&dst);
+            self.record_failure(&dst);
             None
         }
     }
 
-    pub fn find_value(&self, dst: Node, k: String) -> Option<routing::FindValueResult> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindValue(k), dst.clone());
-
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::find_value --> Failed to acquire mutex on Routes");
+    pub async fn find_value(&self, dst: Node, k: String) -> Option<routing::FindValueResult> {
+        let res =
+            utils::make_req_get_res(&self.rpc, network::Request::FindValue(k), dst.clone()).await;
 
         if let Some(network::Response::FindValue(val)) = res {
-            routes.update(dst);
-            Some(val)
+            self.update_routes(dst);
+            match val {
+                routing::FindValueResult::Nodes(entries) => {
+                    Some(routing::FindValueResult::Nodes(advertised(entries)))
+                }
+                val => Some(val),
+            }
         } else {
-            routes.remove(&dst);
+            self.record_failure(&dst);
             None
         }
     }
 
-    pub fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-
-        // nodes visited
-        let mut queried = HashSet::new();
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::nodes_lookup --> Failed to acquire mutex on Routes");
-
-        // nodes to visit
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(id, K_PARAM));
-        drop(routes);
-
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    pub async fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
+        self.disjoint_nodes_lookup(id, DISJOINT_PATHS).await
+    }
 
-        while !to_query.is_empty() {
-            // threads joins
-            let mut joins: Vec<std::thread::JoinHandle<Option<Vec<routing::NodeAndDistance>>>> =
-                Vec::new();
-            // outgoing queries
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<Vec<routing::NodeAndDistance>>> = Vec::new();
+    // keeps ALPHA queries in flight on each path until the k closest nodes it heard of have answered
+    async fn disjoint_nodes_lookup(
+        &self,
+        id: &super::key::Key,
+        paths: usize,
+    ) -> Vec<routing::NodeAndDistance> {
+        let mut lookup = lookup::Lookup::new(
+            id.clone(),
+            {
+                let mut routes = self.routes.lock().expect(
+                    "[FAILED] Protocol::nodes_lookup --> Failed to acquire mutex on Routes",
+                );
+                routes.touch(id);
+                routes.get_closest_nodes(id, K_PARAM)
+            },
+            paths,
+        );
 
-            for _ in 0..ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
+        // outgoing queries, each task hands back its path and the entry it queried along with the result
+        let mut joins: JoinSet<(
+            usize,
+            routing::NodeAndDistance,
+            Option<Vec<routing::NodeAndDistance>>,
+        )> = JoinSet::new();
+
+        while !lookup.is_done() {
+            for path in 0..lookup.paths() {
+                // a slow peer only holds up its own slot
+                while lookup.in_flight(path) < ALPHA {
+                    let Some(entry) = lookup.next(path) else {
                         break;
+                    };
+                    // our table may already hold nodes the lookup is being fed, asking it would
+                    // let them leak into every path
+                    if entry.0.id == self.node.id {
+                        lookup.succeeded(path, &entry, Vec::new());
+                        continue;
                     }
+                    let id_clone = id.clone();
+                    let protocol_clone = self.clone();
+
+                    joins.spawn(async move {
+                        let res = protocol_clone.find_node(entry.0.clone(), id_clone).await;
+                        (path, entry, res)
+                    });
                 }
             }
 
-            for &routing::NodeAndDistance(ref node, _) in &queries {
-                let n = node.clone();
-                let id_clone = id.clone();
-                let protocol_clone = self.clone();
+            let Some(joined) = joins.join_next().await else {
+                break;
+            };
+            let (path, query, result) = joined.expect(
+                "[FAILED] Protocol::nodes_lookup --> Failed to join task while visiting nodes",
+            );
 
-                joins.push(std::thread::spawn(move || {
-                    protocol_clone.find_node(n, id_clone)
-                }));
+            match result {
+                Some(entries) => lookup.succeeded(path, &query, entries),
+                None => lookup.failed(path, &query),
             }
+        }
+
+        // queries still in flight finish on their own, their replies keep the routing table fresh
+        joins.detach_all();
+
+        lookup.responded()
+    }
+
+    // collects up to `wanted` replicas and returns the newest record among them, how many answered,
+    // the closest nodes that don't hold the key and the replicas holding an outdated record
+    pub async fn value_lookup(
+        &self,
+        k: String,
+        wanted: usize,
+    ) -> (
+        Option<Record>,
+        usize,
+        Vec<routing::NodeAndDistance>,
+        Vec<Node>,
+    ) {
+        self.disjoint_value_lookup(k, wanted, DISJOINT_PATHS).await
+    }
+
+    async fn disjoint_value_lookup(
+        &self,
+        k: String,
+        wanted: usize,
+        paths: usize,
+    ) -> (
+        Option<Record>,
+        usize,
+        Vec<routing::NodeAndDistance>,
+        Vec<Node>,
+    ) {
+        // NOTE: k and key are two different things, one is a string used to search for the corresponding value while the other is a key::Key
+
+        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
+        let key = super::key::Key::new(k.clone());
+        // replicas that answered with a value or a tombstone
+        let mut found: Vec<(Node, Record)> = Vec::new();
+
+        let mut lookup = lookup::Lookup::new(
+            key.clone(),
+            {
+                let mut routes = self.routes.lock().expect(
+                    "[FAILED] Protocol::value_lookup --> Failed to acquire mutex on Routes",
+                );
+                routes.touch(&key);
+                routes.get_closest_nodes(&key, K_PARAM)
+            },
+            paths,
+        );
+
+        let mut joins: JoinSet<(
+            usize,
+            routing::NodeAndDistance,
+            Option<routing::FindValueResult>,
+        )> = JoinSet::new();
 
-            for j in joins {
-                results.push(j.join().expect(
-                    "[FAILED] Protocol::nodes_lookup --> Failed to join thread while visiting nodes",
-                ));
+        while !lookup.is_done() && found.len() < wanted {
+            for path in 0..lookup.paths() {
+                while lookup.in_flight(path) < ALPHA {
+                    let Some(entry) = lookup.next(path) else {
+                        break;
+                    };
+                    let k_clone = k.clone();
+                    let protocol = self.clone();
+
+                    joins.spawn(async move {
+                        let res = protocol.find_value(entry.0.clone(), k_clone).await;
+                        (path, entry, res)
+                    });
+                }
             }
 
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(entries) = result {
-                    ret.push(query);
+            let Some(joined) = joins.join_next().await else {
+                break;
+            };
+            let (path, query, result) = joined.expect(
+                "[FAILED] Protocol::value_lookup --> Failed to join task while searching for value",
+            );
 
-                    for entry in entries {
-                        if queried.insert(entry.clone()) {
-                            to_query.push(entry);
-                        }
-                    }
+            let record = match result {
+                None => {
+                    lookup.failed(path, &query);
+                    continue;
                 }
+                Some(routing::FindValueResult::Nodes(entries)) => {
+                    // we didn't get the value we looked for
+                    lookup.succeeded(path, &query, entries);
+                    ret.push(query);
+                    continue;
+                }
+                Some(result) => result
+                    .into_record()
+                    .expect("[FAILED] Protocol::value_lookup --> Replica answered without a record"),
+            };
+
+            // a replica serving a forged record is as good as unresponsive
+            if self.verify_record(&k, &record, &query.0.get_addr()) {
+                lookup.succeeded(path, &query, Vec::new());
+                found.push((query.0, record));
+            } else {
+                lookup.failed(path, &query);
             }
         }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        joins.detach_all();
+
+        ret.sort_by_key(|a| a.1);
         ret.truncate(K_PARAM);
 
-        ret
+        let newest = found
+            .iter()
+            .map(|(_, record)| record)
+            .fold(None, |newest: Option<&Record>, record| match newest {
+                Some(newest) if !record.supersedes(newest) => Some(newest),
+                _ => Some(record),
+            })
+            .cloned();
+        let answers = found.len();
+
+        let stale = match newest {
+            Some(ref newest) => found
+                .into_iter()
+                .filter(|(_, record)| newest.supersedes(record))
+                .map(|(node, _)| node)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        (newest, answers, ret, stale)
     }
 
-    pub fn value_lookup(&self, k: String) -> (Option<String>, Vec<routing::NodeAndDistance>) {
-        // NOTE: k and key are two different things, one is a string used to search for the corresponding value while the other is a key::Key
+    // the next version of a key outranks every copy we can reach
+    async fn next_version(&self, k: &str) -> Version {
+        let (newest, _, _, _) = self.value_lookup(k.to_string(), VALUE_REPLICAS).await;
+        let local = self
+            .store
+            .lock()
+            .expect("[FAILED] Protocol::next_version --> Failed to acquire mutex on Store")
+            .get(k);
 
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-        let key = super::key::Key::new(k.clone());
-        let mut queried = HashSet::new();
+        let seq = [newest, local]
+            .into_iter()
+            .flatten()
+            .map(|record| record.version.seq.saturating_add(1).min(MAX_SEQ))
+            .max()
+            .unwrap_or(0);
 
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::value_lookup --> Failed to acquire mutex on Routes");
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(&key, K_PARAM));
-        drop(routes);
+        Version::new(seq, self.node.id.clone())
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
+    // publishes a record as its original publisher, we keep a copy to republish it every 24h.
+    // Returns None when the key can't be written through put
+    pub async fn put(
+        &self,
+        k: String,
+        v: Vec<u8>,
+        ttl: u64,
+        write_quorum: usize,
+    ) -> Option<Quorum> {
+        if signed::is_signed_key(&k) {
+            eprintln!(
+                "[WARNING] Protocol::put --> Key {} is owned by a public key, use put_signed",
+                k
+            );
+            return None;
+        }
+        if content::is_content_key(&k) {
+            eprintln!(
+                "[WARNING] Protocol::put --> Key {} is content-addressed, use put_immutable",
+                k
+            );
+            return None;
         }
 
-        while !to_query.is_empty() {
-            let mut joins: Vec<std::thread::JoinHandle<Option<routing::FindValueResult>>> =
-                Vec::new();
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<routing::FindValueResult>> = Vec::new();
+        let ttl = ttl.min(RECORD_TTL);
+        let (v, chunk_acks) = self.chunk(v, ttl).await;
+        // the manifest is useless unless every chunk it lists can be found
+        if let Some(acks) = chunk_acks.filter(|acks| *acks < write_quorum) {
+            eprintln!(
+                "[WARNING] Protocol::put --> Write quorum not met for the chunks of {}: {} of {} replicas acknowledged",
+                k, acks, write_quorum
+            );
+            return Some(Quorum {
+                acks,
+                required: write_quorum,
+            });
+        }
+        let version = self.next_version(&k).await;
+        let record = Record::published(v, ttl, version);
+        self.insert_local(&k, record.clone());
+
+        let acks = self.replicate(k.clone(), record).await;
+        let quorum = Quorum {
+            acks: chunk_acks.map_or(acks, |chunk_acks| acks.min(chunk_acks)),
+            required: write_quorum,
+        };
+        if !quorum.is_met() {
+            eprintln!(
+                "[WARNING] Protocol::put --> Write quorum not met for {}: {} of {} replicas acknowledged",
+                k, quorum.acks, quorum.required
+            );
+        }
 
-            for _ in 0..ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
-                        break;
+        Some(quorum)
+    }
+
+    // put in the background, for callers that can't wait on the replicas such as the discovery loop
+    pub fn spawn_put(&self, k: String, v: Vec<u8>, ttl: u64, write_quorum: usize) {
+        let protocol = self.clone();
+        tokio::spawn(async move {
+            protocol.put(k, v, ttl, write_quorum).await;
+        });
+    }
+
+    // publishes a value under the hash of its content, the value can never change. Large values
+    // are chunked like in put and published under the hash of their manifest
+    pub async fn put_immutable(&self, v: Vec<u8>, write_quorum: usize) -> (super::key::Key, Quorum) {
+        let (v, chunk_acks) = self.chunk(v, RECORD_TTL).await;
+        // the manifest is useless unless every chunk it lists can be found
+        if let Some(acks) = chunk_acks.filter(|acks| *acks < write_quorum) {
+            eprintln!(
+                "[WARNING] Protocol::put_immutable --> Write quorum not met for the chunks: {} of {} replicas acknowledged",
+                acks, write_quorum
+            );
+            let quorum = Quorum {
+                acks,
+                required: write_quorum,
+            };
+            return (super::key::Key::hash(&v), quorum);
+        }
+
+        // the value never changes, every copy has the same version whoever stored it, so storing
+        // it again only ever extends its lifetime
+        let record = Record::published(v, RECORD_TTL, Version::default());
+        let (hash, acks) = self.store_immutable(record).await;
+        let quorum = Quorum {
+            acks: chunk_acks.map_or(acks, |chunk_acks| acks.min(chunk_acks)),
+            required: write_quorum,
+        };
+        if !quorum.is_met() {
+            eprintln!(
+                "[WARNING] Protocol::put_immutable --> Write quorum not met for {}: {} of {} replicas acknowledged",
+                hex::encode(hash.0), quorum.acks, quorum.required
+            );
+        }
+
+        (hash, quorum)
+    }
+
+    // stores a record under the hash of its value and returns the hash along with the number of
+    // replicas that acknowledged it
+    async fn store_immutable(&self, record: Record) -> (super::key::Key, usize) {
+        let hash = super::key::Key::hash(&record.value);
+        let k = content::key_for(&hash);
+
+        self.insert_local(&k, record.clone());
+        let acks = self.replicate(k, record).await;
+        (hash, acks)
+    }
+
+    // the hash of every value returned is checked by value_lookup, manifests are reassembled
+    pub async fn get_immutable(&self, hash: &super::key::Key) -> Option<Vec<u8>> {
+        let value = self.fetch_chunk(hash).await;
+        self.expand(value).await
+    }
+
+    // chunks are returned as is, the ones of a nested manifest start like a manifest
+    async fn fetch_chunk(&self, hash: &super::key::Key) -> Option<Vec<u8>> {
+        self.fetch(content::key_for(hash), READ_QUORUM).await.0
+    }
+
+    // stores large values as immutable chunks and returns the manifest to publish in their place,
+    // the chunks live as long as the manifest. Also returns the fewest acks any chunk got, None
+    // when the value is small enough to be stored as is
+    async fn chunk(&self, mut v: Vec<u8>, ttl: u64) -> (Vec<u8>, Option<usize>) {
+        if !chunks::needs_chunking(&v) {
+            return (v, None);
+        }
+
+        let mut nested = false;
+        let mut min_acks = usize::MAX;
+        loop {
+            let mut joins = JoinSet::new();
+            for chunk in v.chunks(CHUNK_SIZE) {
+                if joins.len() >= CHUNK_PARALLELISM {
+                    if let Some(joined) = joins.join_next().await {
+                        let (_, acks) = joined.expect(
+                            "[FAILED] Protocol::chunk --> Failed to join task while storing chunks",
+                        );
+                        min_acks = min_acks.min(acks);
                     }
                 }
-            }
 
-            for &routing::NodeAndDistance(ref n, _) in &queries {
-                let k_clone = k.clone();
-                let node = n.clone();
                 let protocol = self.clone();
+                // chunks aren't republished on their own but along with their manifest
+                let record = Record::new(chunk.to_vec(), ttl, Version::default());
+                joins.spawn(async move { protocol.store_immutable(record).await });
+            }
+            while let Some(joined) = joins.join_next().await {
+                let (_, acks) = joined.expect(
+                    "[FAILED] Protocol::chunk --> Failed to join task while storing chunks",
+                );
+                min_acks = min_acks.min(acks);
+            }
 
-                joins.push(std::thread::spawn(move || {
-                    protocol.find_value(node, k_clone)
-                }));
+            let manifest = chunks::Manifest::new(&v, nested).encode();
+            if manifest.len() <= CHUNK_SIZE {
+                return (manifest, Some(min_acks));
             }
+            v = manifest;
+            nested = true;
+        }
+    }
 
-            for j in joins {
-                results.push(j.join().expect("[FAILED] Protocol::value_lookup --> Failed to join thread while searching for value"));
+    // fetches the chunks listed by a manifest, each one is verified against its hash by the lookup
+    async fn reassemble(&self, manifest: &chunks::Manifest) -> Option<Vec<u8>> {
+        let mut joins = JoinSet::new();
+        let mut parts = Vec::new();
+        let mut pending = manifest.chunks.iter().cloned().enumerate();
+
+        loop {
+            while joins.len() < CHUNK_PARALLELISM {
+                let Some((i, hash)) = pending.next() else {
+                    break;
+                };
+                let protocol = self.clone();
+                joins.spawn(async move { (i, protocol.fetch_chunk(&hash).await) });
             }
 
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(find_value_result) = result {
-                    match find_value_result {
-                        routing::FindValueResult::Nodes(entries) => {
-                            // we didn't get the value we looked for
-                            ret.push(query);
-                            for entry in entries {
-                                if queried.insert(entry.clone()) {
-                                    to_query.push(entry);
-                                }
-                            }
-                        }
+            let Some(joined) = joins.join_next().await else {
+                break;
+            };
+            let (i, chunk) = joined.expect(
+                "[FAILED] Protocol::reassemble --> Failed to join task while fetching chunks",
+            );
+            match chunk {
+                Some(chunk) => parts.push((i, chunk)),
+                None => {
+                    eprintln!(
+                        "[WARNING] Protocol::reassemble --> Chunk {} could not be found",
+                        i
+                    );
+                    return None;
+                }
+            }
+        }
+        parts.sort_by_key(|(i, _)| *i);
 
-                        routing::FindValueResult::Value(val) => {
-                            ret.sort_by(|a, b| a.1.cmp(&b.1));
-                            ret.truncate(K_PARAM);
+        let value: Vec<u8> = parts.into_iter().flat_map(|(_, chunk)| chunk).collect();
+        if value.len() as u64 != manifest.len {
+            eprintln!(
+                "[WARNING] Protocol::reassemble --> Expected {} bytes but got {}",
+                manifest.len,
+                value.len()
+            );
+            return None;
+        }
 
-                            return (Some(val), ret);
-                        }
-                    }
+        Some(value)
+    }
+
+    // publishes a value under the key owned by our signer, only that key can update it later on
+    pub async fn put_signed(&self, v: Vec<u8>, ttl: u64) -> Option<String> {
+        let signer = self.signer.as_ref()?.key();
+        let k = signed::key_for(&signer.public());
+        let ttl = ttl.min(RECORD_TTL);
+        let (v, _) = self.chunk(v, ttl).await;
+        let version = self.next_version(&k).await;
+        let mut record = Record::published(v, ttl, version);
+        record.signature = Some(signed::sign(signer, &k, &record)?);
+
+        self.insert_local(&k, record.clone());
+        self.replicate(k.clone(), record).await;
+        Some(k)
+    }
+
+    // deletes the key by leaving tombstones on the k closest nodes and on ourselves.
+    // Returns None when the key can't be deleted
+    pub async fn remove(&self, k: String, write_quorum: usize) -> Option<Quorum> {
+        if signed::is_signed_key(&k) {
+            eprintln!("[WARNING] Protocol::remove --> Key {} is owned by a public key, it can't be deleted", k);
+            return None;
+        }
+        if content::is_content_key(&k) {
+            eprintln!(
+                "[WARNING] Protocol::remove --> Key {} is content-addressed, it can't be deleted",
+                k
+            );
+            return None;
+        }
+
+        let version = self.next_version(&k).await;
+        let record = Record::tombstone(TOMBSTONE_TTL, version);
+        self.insert_local(&k, record.clone());
+
+        let quorum = Quorum {
+            acks: self.replicate(k.clone(), record).await,
+            required: write_quorum,
+        };
+        if !quorum.is_met() {
+            eprintln!(
+                "[WARNING] Protocol::remove --> Write quorum not met for {}: {} of {} replicas acknowledged",
+                k, quorum.acks, quorum.required
+            );
+        }
+
+        Some(quorum)
+    }
+
+    // the value is only returned once `read_quorum` replicas answered, values stored as a manifest
+    // are reassembled from their chunks
+    pub async fn get(&self, k: String, read_quorum: usize) -> (Option<Vec<u8>>, Quorum) {
+        let (value, quorum) = self.fetch(k.clone(), read_quorum).await;
+        if !quorum.is_met() {
+            eprintln!(
+                "[WARNING] Protocol::get --> Read quorum not met for {}: {} of {} replicas answered",
+                k, quorum.acks, quorum.required
+            );
+            return (None, quorum);
+        }
+
+        (self.expand(value).await, quorum)
+    }
+
+    async fn expand(&self, value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let mut value = value?;
+
+        if let Some(mut manifest) = chunks::Manifest::decode(&value) {
+            loop {
+                value = self.reassemble(&manifest).await?;
+                if !manifest.nested {
+                    break;
                 }
+                manifest = chunks::Manifest::decode(&value)?;
             }
         }
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(K_PARAM);
-        (None, ret)
+
+        Some(value)
     }
 
-    pub fn put(&self, k: String, v: String) {
-        let candidates = self.nodes_lookup(&super::key::Key::new(k.clone()));
+    // reads the newest record among max(read_quorum, VALUE_REPLICAS) replicas
+    async fn fetch(&self, k: String, read_quorum: usize) -> (Option<Vec<u8>>, Quorum) {
+        let (newest, answers, mut nodes, stale) = self
+            .value_lookup(k.clone(), read_quorum.max(VALUE_REPLICAS))
+            .await;
+        let quorum = Quorum {
+            acks: answers,
+            required: read_quorum,
+        };
+        let Some(record) = newest else {
+            return (None, quorum);
+        };
 
-        for routing::NodeAndDistance(node, _) in candidates {
+        // replicas holding an older value or tombstone are brought up to date
+        for node in stale {
             let protocol_clone = self.clone();
             let k_clone = k.clone();
-            let v_clone = v.clone();
+            let record_clone = record.clone();
 
-            std::thread::spawn(move || {
-                protocol_clone.store(node, k_clone, v_clone);
+            tokio::spawn(async move {
+                protocol_clone.push(node, k_clone, &record_clone).await;
             });
         }
+
+        if record.deleted {
+            return (None, quorum);
+        }
+
+        // caching keeps the remaining lifetime so the copy expires along with the original
+        if let Some(routing::NodeAndDistance(target, _)) = nodes.pop() {
+            self.push(target, k, &record).await;
+        } else {
+            self.push(self.node.clone(), k, &record).await;
+        }
+
+        (Some(record.value), quorum)
     }
+}
+
+// drops the nodes a peer handed out at an address their own record doesn't advertise, any
+// public ENR could otherwise be paired with the address of whoever wants to receive its queries
+fn advertised(entries: Vec<routing::NodeAndDistance>) -> Vec<routing::NodeAndDistance> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.0.advertises_address())
+        .collect()
+}
 
-    pub fn get(&self, k: String) -> Option<String> {
-        let (val, mut nodes) = self.value_lookup(k.clone());
+// who a record pushed to us is charged to: the owner of a signed key, whose signature was checked,
+// otherwise the address (or /64) it came from. The publisher in the version is picked by the sender
+fn quota_owner(k: &str, src: &str) -> String {
+    if signed::is_signed_key(k) {
+        return k.to_string();
+    }
 
-        val.map(|v| {
-            if let Some(routing::NodeAndDistance(target, _)) = nodes.pop() {
-                self.store(target, k, v.clone());
-            } else {
-                self.store(self.node.clone(), k, v.clone());
+    match src.parse::<SocketAddr>() {
+        Ok(addr) => ratelimit::peer(addr.ip()).to_string(),
+        Err(_) => src.to_string(),
+    }
+}
+
+// content keys of the chunks a manifest value lists, nested manifests are followed through the
+// chunks we hold, which the node that stored them always does
+fn chunk_keys(store: &dyn Storage, value: &[u8]) -> Vec<String> {
+    let mut keys = Vec::new();
+    let Some(mut manifest) = chunks::Manifest::decode(value) else {
+        return keys;
+    };
+
+    loop {
+        keys.extend(manifest.chunks.iter().map(content::key_for));
+        if !manifest.nested {
+            return keys;
+        }
+
+        let inner: Option<Vec<Vec<u8>>> = manifest
+            .chunks
+            .iter()
+            .map(|hash| store.get(&content::key_for(hash)).map(|chunk| chunk.value))
+            .collect();
+        match inner.and_then(|parts| chunks::Manifest::decode(&parts.concat())) {
+            Some(inner) => manifest = inner,
+            None => return keys,
+        }
+    }
+}
+
+// once a value we published is overwritten or deleted, nothing republishes its chunks anymore:
+// our copies are dropped and the other replicas let theirs expire. Chunks the new value shares
+// with the old one are kept
+fn release_chunks(store: &mut dyn Storage, old: &Record, new: &Record) {
+    if old.publish_ttl.is_none() || old.deleted || old.version == new.version {
+        return;
+    }
+
+    let kept = if new.deleted {
+        Vec::new()
+    } else {
+        chunk_keys(store, &new.value)
+    };
+    for k in chunk_keys(store, &old.value) {
+        // a chunk also posted as a blob of its own is republished for that blob
+        let published = store.get(&k).is_some_and(|chunk| chunk.publish_ttl.is_some());
+        if published || kept.contains(&k) {
+            continue;
+        }
+        if let Err(e) = store.remove(&k) {
+            eprintln!(
+                "[FAILED] Protocol::release_chunks --> Unable to drop chunk {}: {}",
+                k, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::key::{Distance, Key};
+    use super::super::testutil::{make_node, spawn_node};
+    use super::*;
+
+    // ids come from the node key, landing next to a target takes generating keys until one shares
+    // its first 4 bits
+    fn grind_node(port: u16, target: &Key) -> Node {
+        loop {
+            let node = make_node(port);
+            if Distance::new(&node.id, target).0[0] < 0x10 {
+                return node;
             }
+        }
+    }
 
-            v
-        })
+    fn entries(nodes: &[Node], target: &Key) -> Vec<routing::NodeAndDistance> {
+        nodes
+            .iter()
+            .map(|node| routing::NodeAndDistance(node.clone(), Distance::new(&node.id, target)))
+            .collect()
+    }
+
+    // answers every lookup with sybils ground next to the target, all of them served from the
+    // adversary's socket
+    async fn spawn_adversary(port: u16, target: &Key) -> Node {
+        let node = grind_node(port, target);
+        let sybils: Vec<Node> = (0..K_PARAM).map(|_| grind_node(port, target)).collect();
+        let rpc = network::Rpc::new(
+            node.clone(),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            network::WireFormat::Binary,
+        )
+        .await
+        .unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        network::Rpc::open(rpc.clone(), sender);
+
+        let adversary = node.clone();
+        tokio::spawn(async move {
+            while let Some(req) = receiver.recv().await {
+                let res = match req.payload {
+                    network::Request::FindNode(id) => {
+                        network::Response::FindNode(entries(&sybils, &id))
+                    }
+                    network::Request::FindValue(k) => network::Response::FindValue(
+                        routing::FindValueResult::Nodes(entries(&sybils, &Key::new(k))),
+                    ),
+                    _ => network::Response::Ping,
+                };
+                let msg = network::RpcMessage {
+                    token: req.token,
+                    src: adversary.get_addr(),
+                    dst: req.src,
+                    msg: network::Message::Response(res),
+                    enr: Some(adversary.enr.clone()),
+                };
+                rpc.send_msg(&msg, req.format).await;
+            }
+        });
+
+        node
     }
-}
\ No newline at end of file
+
+    // honest nodes, two lookup origins that both know the adversary, the adversary and the target
+    // it ground its sybils for. No other node shares the first 4 bits of the target
+    async fn spawn_network(base_port: u16) -> (Vec<Protocol>, Protocol, Protocol, Node, Key) {
+        let first = spawn_node(base_port, None).await;
+        let mut honest = vec![first.clone()];
+        for i in 1..6 {
+            honest.push(spawn_node(base_port + i, Some(first.node.clone())).await);
+        }
+        let plain = spawn_node(base_port + 7, Some(first.node.clone())).await;
+        let disjoint = spawn_node(base_port + 8, Some(first.node.clone())).await;
+
+        let target = loop {
+            let target = Key(rand::random());
+            if honest
+                .iter()
+                .chain([&plain, &disjoint])
+                .all(|protocol| Distance::new(&protocol.node.id, &target).0[0] >= 0x10)
+            {
+                break target;
+            }
+        };
+
+        let adversary = spawn_adversary(base_port + 6, &target).await;
+        plain.update_routes(adversary.clone());
+        disjoint.update_routes(adversary.clone());
+
+        (honest, plain, disjoint, adversary, target)
+    }
+
+    fn is_honest(honest: &[Protocol], node: &Node) -> bool {
+        honest.iter().any(|protocol| protocol.node.id == node.id)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disjoint_paths_survive_fake_closer_nodes() {
+        // the adversary is the first node both origins ask
+        let (honest, plain, disjoint, _, target) = spawn_network(47301).await;
+
+        // the paths that didn't start at the adversary still reach the closest honest node. This runs
+        // first, the plain origin adds the fake nodes to its table and would hand them out
+        let closest_honest = honest
+            .iter()
+            .min_by_key(|protocol| Distance::new(&protocol.node.id, &target))
+            .unwrap();
+        let found = disjoint.disjoint_nodes_lookup(&target, 3).await;
+        assert!(found
+            .iter()
+            .any(|entry| entry.0.id == closest_honest.node.id));
+        assert!(
+            found
+                .iter()
+                .filter(|entry| is_honest(&honest, &entry.0))
+                .count()
+                >= 2
+        );
+
+        // a single path is taken over entirely
+        let hijacked = plain.disjoint_nodes_lookup(&target, 1).await;
+        assert_eq!(hijacked.len(), K_PARAM);
+        assert!(hijacked.iter().all(|entry| !is_honest(&honest, &entry.0)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disjoint_value_lookup_finds_the_value() {
+        let (honest, _, disjoint, adversary, _) = spawn_network(47321).await;
+
+        // a key the adversary is the first node asked for
+        let k = (0..)
+            .map(|i| format!("key-{}", i))
+            .find(|k| {
+                let key = Key::new(k.clone());
+                let routes = disjoint.routes.lock().unwrap();
+                routes
+                    .get_closest_nodes(&key, K_PARAM)
+                    .into_iter()
+                    .min_by_key(|entry| entry.1)
+                    .is_some_and(|entry| entry.0.id == adversary.id)
+            })
+            .unwrap();
+
+        let record = Record::new(
+            b"value".to_vec(),
+            3600,
+            Version::new(1, honest[0].node.id.clone()),
+        );
+        for protocol in &honest {
+            protocol.insert_local(&k, record.clone());
+        }
+
+        let (found, answers, _, _) = disjoint.disjoint_value_lookup(k, 1, 3).await;
+        assert_eq!(found.map(|record| record.value), Some(b"value".to_vec()));
+        assert!(answers >= 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn published_records_outlive_their_ttl() {
+        let publisher = spawn_node(47341, None).await;
+        let k = "republished".to_string();
+        let quorum = publisher.put(k.clone(), b"value".to_vec(), 3, 1).await;
+        assert_eq!(quorum.map(|quorum| quorum.is_met()), Some(true));
+
+        // the next pass would come after the record expired, so this one refreshes it
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        publisher.republish().await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        publisher.sweep();
+
+        let record = publisher.store.lock().unwrap().get(&k);
+        assert!(record.is_some_and(|record| !record.is_expired()));
+    }
+
+    #[tokio::test]
+    async fn chunks_live_as_long_as_their_manifest() {
+        let publisher = spawn_node(47351, None).await;
+        let k = "chunked".to_string();
+        let value: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i / CHUNK_SIZE) as u8).collect();
+        let quorum = publisher.put(k.clone(), value.clone(), 3, 1).await;
+        assert_eq!(quorum.map(|quorum| quorum.is_met()), Some(true));
+
+        let chunk_keys: Vec<String> = value
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| content::key_for(&crate::dht::key::Key::hash(chunk)))
+            .collect();
+
+        // the chunks are refreshed along with the manifest
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        publisher.republish().await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        publisher.sweep();
+        for ck in &chunk_keys {
+            let chunk = publisher.store.lock().unwrap().get(ck);
+            assert!(chunk.is_some_and(|chunk| !chunk.is_expired()));
+        }
+
+        // and dropped once it is overwritten
+        let quorum = publisher.put(k.clone(), b"small".to_vec(), 3, 1).await;
+        assert_eq!(quorum.map(|quorum| quorum.is_met()), Some(true));
+        for ck in &chunk_keys {
+            assert!(publisher.store.lock().unwrap().get(ck).is_none());
+        }
+    }
+}
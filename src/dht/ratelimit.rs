@@ -0,0 +1,125 @@
+use super::config::{
+    DATAGRAM_RATE_LIMIT, FIND_RATE_LIMIT, MAX_TRACKED_PEERS, PING_RATE_LIMIT, STORE_RATE_LIMIT,
+    STREAM_RATE_LIMIT,
+};
+use super::network::Request;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Instant;
+
+// inbound messages limited separately, so a flood of stores doesn't starve lookups
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    Ping,
+    Store,
+    Delete,
+    FindNode,
+    FindValue,
+    // announcement of a message to fetch over the stream side channel
+    Stream,
+    // any datagram, checked before it is decoded
+    Datagram,
+}
+
+impl RequestKind {
+    pub fn of(req: &Request) -> Self {
+        match req {
+            Request::Ping => RequestKind::Ping,
+            Request::Store(..) => RequestKind::Store,
+            Request::Delete(..) => RequestKind::Delete,
+            Request::FindNode(..) => RequestKind::FindNode,
+            Request::FindValue(..) => RequestKind::FindValue,
+        }
+    }
+
+    // tokens refilled per second and size of the bucket
+    fn limit(&self) -> (f64, f64) {
+        match self {
+            RequestKind::Ping => PING_RATE_LIMIT,
+            RequestKind::Store | RequestKind::Delete => STORE_RATE_LIMIT,
+            RequestKind::FindNode | RequestKind::FindValue => FIND_RATE_LIMIT,
+            RequestKind::Stream => STREAM_RATE_LIMIT,
+            RequestKind::Datagram => DATAGRAM_RATE_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    // last message taken from the bucket, refills don't count
+    used: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant, (rate, burst): (f64, f64)) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+// a single host owns a whole /64, so IPv6 peers are limited by prefix
+pub fn peer(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+        ip => ip,
+    }
+}
+
+// token buckets per source address and message kind
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(IpAddr, RequestKind), TokenBucket>,
+}
+
+impl RateLimiter {
+    // takes a token from the bucket of the address, false when the message must be dropped
+    pub fn allow(&mut self, ip: IpAddr, kind: RequestKind) -> bool {
+        let now = Instant::now();
+        let id = (peer(ip), kind);
+
+        if !self.buckets.contains_key(&id) && self.buckets.len() >= MAX_TRACKED_PEERS {
+            // full buckets carry no state worth keeping
+            self.buckets.retain(|(_, kind), bucket| {
+                let limit = kind.limit();
+                bucket.refill(now, limit);
+                bucket.tokens < limit.1
+            });
+
+            // every tracked peer is busy, the quarter used least recently makes room so new peers
+            // aren't locked out, evicting in batches keeps the scan off the path of every new address
+            if self.buckets.len() >= MAX_TRACKED_PEERS {
+                let mut idle: Vec<_> = self
+                    .buckets
+                    .iter()
+                    .map(|(id, bucket)| (bucket.used, *id))
+                    .collect();
+                let evicted = MAX_TRACKED_PEERS / 4;
+                idle.select_nth_unstable_by_key(evicted, |(used, _)| *used);
+                for (_, id) in &idle[..evicted] {
+                    self.buckets.remove(id);
+                }
+            }
+        }
+
+        let limit = kind.limit();
+        let bucket = self.buckets.entry(id).or_insert(TokenBucket {
+            tokens: limit.1,
+            updated: now,
+            used: now,
+        });
+        bucket.refill(now, limit);
+        bucket.used = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
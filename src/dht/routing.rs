@@ -1,10 +1,15 @@
 use super::key::{Distance, Key};
-use super::network;
 use super::node::Node;
-use super::utils::ChannelPayload;
-use super::config::{K_PARAM, N_BUCKETS, KEY_LEN};
-use crossbeam_channel;
+use super::signed::RecordSignature;
+use super::storage::{Record, Version};
+use super::config::{
+    K_PARAM, N_BUCKETS, KEY_LEN, MAX_FAILURES, MAX_SUBNET_NODES_PER_BUCKET, MAX_SUBNET_NODES_PER_TABLE,
+    REPLACEMENT_CACHE_SIZE,
+};
+use super::utils;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, Serialize, Deserialize, Eq, Hash, Clone)]
 pub struct NodeAndDistance(pub Node, pub Distance);
@@ -12,21 +17,126 @@ pub struct NodeAndDistance(pub Node, pub Distance);
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FindValueResult {
     Nodes(Vec<NodeAndDistance>),
-    Value(String),
+    // value, its remaining time-to-live in seconds, its version and signature
+    Value(Vec<u8>, u64, Version, Option<RecordSignature>),
+    // the key was deleted, remaining time-to-live of the tombstone in seconds, its version and signature
+    Deleted(u64, Version, Option<RecordSignature>),
 }
 
 #[derive(Debug)]
 pub struct KBucket {
     pub nodes: Vec<Node>,
+    // candidates waiting for a slot in the bucket, most recently seen last
+    pub replacements: Vec<Node>,
     pub size: usize,
+    // whether the least recently seen node is being pinged
+    pub checking: bool,
+    // consecutive timeouts of the contacts in the bucket, cleared on any reply
+    pub failures: HashMap<Key, u32>,
+    // unix time of the last lookup for an id falling into the bucket
+    pub last_lookup: u64,
 }
 
 #[derive(Debug)]
 pub struct RoutingTable {
     pub node: Node,
     pub kbuckets: Vec<KBucket>,
-    pub sender: crossbeam_channel::Sender<ChannelPayload>,
-    pub receiver: crossbeam_channel::Receiver<ChannelPayload>,
+    pub limits: IpLimits,
+}
+
+// most contacts a single bucket and the whole table accept from the same /24 (IPv4) or /64 (IPv6),
+// so a single host can't fill our buckets
+#[derive(Clone, Copy, Debug)]
+pub struct IpLimits {
+    pub bucket: usize,
+    pub table: usize,
+}
+
+impl Default for IpLimits {
+    fn default() -> Self {
+        Self {
+            bucket: MAX_SUBNET_NODES_PER_BUCKET,
+            table: MAX_SUBNET_NODES_PER_TABLE,
+        }
+    }
+}
+
+impl IpLimits {
+    // for local networks where every node runs on the same host
+    pub fn unlimited() -> Self {
+        Self {
+            bucket: usize::MAX,
+            table: usize::MAX,
+        }
+    }
+}
+
+// the /24 of an IPv4 address or the /64 of an IPv6 one, None when the ip can't be parsed
+fn subnet(ip: &str) -> Option<IpAddr> {
+    match ip.parse::<IpAddr>().ok()?.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Some(IpAddr::V4(Ipv4Addr::new(a, b, c, 0)))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            Some(IpAddr::V6(Ipv6Addr::new(
+                s[0], s[1], s[2], s[3], 0, 0, 0, 0,
+            )))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateResult {
+    // the node was already in its bucket and got moved to the tail
+    Updated,
+    Added,
+    // the bucket is full, the node went to the replacement cache
+    Cached,
+    // too many contacts from the same subnet already or the node isn't reached at the address
+    // its record advertises, the node was dropped
+    Rejected,
+    // same as Cached, but the caller must check the liveness of the returned
+    // least recently seen node and report back through RoutingTable::resolve_check
+    CheckLiveness(Node),
+}
+
+impl FindValueResult {
+    // records travel with their remaining lifetime instead of their absolute expiry
+    pub fn from_record(record: &Record) -> Self {
+        if record.deleted {
+            FindValueResult::Deleted(record.ttl(), record.version.clone(), record.signature.clone())
+        } else {
+            FindValueResult::Value(
+                record.value.clone(),
+                record.ttl(),
+                record.version.clone(),
+                record.signature.clone(),
+            )
+        }
+    }
+
+    // the record a replica answered with, None when it only knew closer nodes
+    pub fn into_record(self) -> Option<Record> {
+        match self {
+            FindValueResult::Nodes(_) => None,
+            FindValueResult::Deleted(ttl, version, signature) => Some(
+                Record {
+                    signature,
+                    ..Record::tombstone(ttl, version)
+                }
+                .cap_lifetime(),
+            ),
+            FindValueResult::Value(val, ttl, version, signature) => Some(
+                Record {
+                    signature,
+                    ..Record::new(val, ttl, version)
+                }
+                .cap_lifetime(),
+            ),
+        }
+    }
 }
 
 impl PartialEq for NodeAndDistance {
@@ -63,18 +173,26 @@ impl KBucket {
     pub fn new() -> Self {
         Self {
             nodes: Vec::new(),
+            replacements: Vec::new(),
             size: K_PARAM,
+            checking: false,
+            failures: HashMap::new(),
+            last_lookup: utils::now_secs(),
+        }
+    }
+
+    fn add_replacement(&mut self, node: Node) {
+        if let Some(i) = self.replacements.iter().position(|x| x.id == node.id) {
+            self.replacements.remove(i);
+        } else if self.replacements.len() >= REPLACEMENT_CACHE_SIZE {
+            self.replacements.remove(0);
         }
+        self.replacements.push(node);
     }
 }
 
 impl RoutingTable {
-    pub fn new(
-        node: Node,
-        bootstrap: Option<Node>,
-        sender: crossbeam_channel::Sender<ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<ChannelPayload>,
-    ) -> Self {
+    pub fn new(node: Node, bootstrap: Option<Node>, limits: IpLimits) -> Self {
         let mut kbuckets: Vec<KBucket> = Vec::new();
         for _ in 0..N_BUCKETS {
             kbuckets.push(KBucket::new());
@@ -83,8 +201,7 @@ impl RoutingTable {
         let mut ret = Self {
             node: node.clone(),
             kbuckets,
-            sender,
-            receiver,
+            limits,
         };
 
         ret.update(node);
@@ -118,77 +235,179 @@ impl RoutingTable {
         KEY_LEN * 8 - 1
     }
 
-    fn contact_via_rpc(&self, dst: Node) -> bool {
-        if let Err(_) = self
-            .sender
-            .send(ChannelPayload::Request((network::Request::Ping, dst)))
-        {
-            println!(
-                "[FAILED] RoutingTable::contact_via_rpc --> Receiver is dead, closing channel"
-            );
-            return false;
+    // never blocks: when the bucket is full the node is cached and the caller is asked to ping
+    // the least recently seen node, so the table lock is never held across network I/O
+    pub fn update(&mut self, node: Node) -> UpdateResult {
+        // our own entry is the only one not learnt from the network
+        if node.id != self.node.id && !node.advertises_address() {
+            return UpdateResult::Rejected;
+        }
+        if !self.admits(&node) {
+            return UpdateResult::Rejected;
         }
 
-        true
+        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
+
+        if let Some(i) = bucket.nodes.iter().position(|x| x.id == node.id) {
+            bucket.failures.remove(&node.id);
+            let known = bucket.nodes.remove(i);
+            // a stale record handed out by a third node doesn't replace the newer one we hold
+            let node = if known.enr.seq() > node.enr.seq() { known } else { node };
+            bucket.nodes.push(node);
+            return UpdateResult::Updated;
+        }
+
+        if bucket.nodes.len() < bucket.size {
+            bucket.nodes.push(node);
+            return UpdateResult::Added;
+        }
+
+        bucket.add_replacement(node);
+        if bucket.checking {
+            return UpdateResult::Cached;
+        }
+
+        bucket.checking = true;
+        UpdateResult::CheckLiveness(bucket.nodes[0].clone())
     }
 
-    pub fn update(&mut self, node: Node) {
+    // whether the ip limits leave room for the node, contacts already known are always admitted
+    // and our own entry is never counted
+    pub fn admits(&self, node: &Node) -> bool {
         let bucket_idx = self.get_lookup_bucket_index(&node.id);
-
-        // TODO(testing): fill buckets with dummy nodes so we can reach the else statement
-        if self.kbuckets[bucket_idx].nodes.len() < K_PARAM {
-            let node_idx = self.kbuckets[bucket_idx]
+        if node.id == self.node.id
+            || self.kbuckets[bucket_idx]
                 .nodes
                 .iter()
-                .position(|x| x.id == node.id);
-            match node_idx {
-                Some(i) => {
-                    self.kbuckets[bucket_idx].nodes.remove(i);
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-                None => {
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
+                .any(|x| x.id == node.id)
+        {
+            return true;
+        }
+        let Some(net) = subnet(&node.ip) else {
+            return true;
+        };
+
+        let same_subnet = |x: &&Node| x.id != self.node.id && subnet(&x.ip) == Some(net);
+        let in_bucket = self.kbuckets[bucket_idx]
+            .nodes
+            .iter()
+            .filter(same_subnet)
+            .count();
+        let in_table = self
+            .kbuckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(same_subnet)
+            .count();
+
+        in_bucket < self.limits.bucket && in_table < self.limits.table
+    }
+
+    // fills a free slot with the most recently seen candidate the ip limits still admit
+    fn promote_replacement(&mut self, bucket_idx: usize) {
+        let bucket = &self.kbuckets[bucket_idx];
+        if bucket.nodes.len() >= bucket.size {
+            return;
+        }
+        let Some(i) = bucket.replacements.iter().rposition(|x| self.admits(x)) else {
+            return;
+        };
+
+        let bucket = &mut self.kbuckets[bucket_idx];
+        let node = bucket.replacements.remove(i);
+        bucket.nodes.push(node);
+    }
+
+    // outcome of the liveness check requested by update
+    pub fn resolve_check(&mut self, node: &Node, alive: bool) {
+        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        self.kbuckets[bucket_idx].checking = false;
+
+        if alive {
+            let bucket = &mut self.kbuckets[bucket_idx];
+            bucket.failures.remove(&node.id);
+            if let Some(i) = bucket.nodes.iter().position(|x| x.id == node.id) {
+                let to_re_add = bucket.nodes.remove(i);
+                bucket.nodes.push(to_re_add);
             }
         } else {
-            let _success = self.contact_via_rpc(self.kbuckets[bucket_idx].nodes[0].clone());
-            let receiver = self.receiver.clone();
-
-            // We must make it blocking (no threads) because we still want to use the mutable reference to the buckets
-            // we don't want to clone and move
-            let res = receiver
-                .recv()
-                .expect("[FAILED] Routing::update --> Failed  to receive data from channel");
-            match res {
-                ChannelPayload::Response(_) => {
-                    let to_re_add = self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(to_re_add);
-                }
-                ChannelPayload::Request(_) => {
-                    eprintln!("[FAILED] Routing::update --> Unexpectedly got a Request instead of a Response");
-                }
-                ChannelPayload::NoData => {
-                    self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-            };
+            self.record_failure(node);
         }
     }
 
-    pub fn remove(&mut self, node: &Node) {
+    // counts a timeout of the node and drops it once it missed MAX_FAILURES replies in a row,
+    // returns whether it was dropped
+    pub fn record_failure(&mut self, node: &Node) -> bool {
         let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
 
-        if let Some(i) = self.kbuckets[bucket_idx]
-            .nodes
-            .iter()
-            .position(|x| x.id == node.id)
-        {
-            self.kbuckets[bucket_idx].nodes.remove(i);
+        if !bucket.nodes.iter().any(|x| x.id == node.id) {
+            // cached candidates have no slot to lose, most lookup queries go to nodes we don't know at all
+            if let Some(i) = bucket.replacements.iter().position(|x| x.id == node.id) {
+                bucket.replacements.remove(i);
+            }
+            return false;
+        }
+
+        let failures = bucket.failures.entry(node.id.clone()).or_insert(0);
+        *failures += 1;
+        if *failures < MAX_FAILURES {
+            return false;
+        }
+
+        self.remove(node);
+        true
+    }
+
+    pub fn remove(&mut self, node: &Node) {
+        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
+
+        bucket.failures.remove(&node.id);
+        if let Some(i) = bucket.nodes.iter().position(|x| x.id == node.id) {
+            bucket.nodes.remove(i);
+            self.promote_replacement(bucket_idx);
+        } else if let Some(i) = bucket.replacements.iter().position(|x| x.id == node.id) {
+            bucket.replacements.remove(i);
         } else {
             eprintln!("[WARN] Routing::remove --> Tried to remove non-existing entry");
         }
     }
 
+    // marks the bucket the key falls into as refreshed
+    pub fn touch(&mut self, key: &Key) {
+        let bucket_idx = self.get_lookup_bucket_index(key);
+        self.kbuckets[bucket_idx].last_lookup = utils::now_secs();
+    }
+
+    // indexes of the non-empty buckets without a lookup for max_age seconds
+    pub fn stale_buckets(&self, max_age: u64) -> Vec<usize> {
+        let now = utils::now_secs();
+        self.kbuckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.nodes.is_empty() && now.saturating_sub(b.last_lookup) >= max_age)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // random id falling into the bucket with the given index, mirrors get_lookup_bucket_index:
+    // byte i of the distance has bit j (counted from the most significant one) as its lowest set
+    // bit while the previous bytes are zero
+    pub fn random_key_in_bucket(&self, bucket_idx: usize) -> Key {
+        let (i, j) = (bucket_idx / 8, bucket_idx % 8);
+        let mut d: [u8; KEY_LEN] = rand::random();
+
+        for byte in d.iter_mut().take(i) {
+            *byte = 0;
+        }
+        let bit = 0x80u8 >> j;
+        d[i] = (d[i] & !(bit | (bit - 1))) | bit;
+
+        Key(std::array::from_fn(|k| self.node.id.0[k] ^ d[k]))
+    }
+
     pub fn get_closest_nodes(&self, key: &Key, count: usize) -> Vec<NodeAndDistance> {
         /*
             Notes:
@@ -237,7 +456,7 @@ impl RoutingTable {
             }
         }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        ret.sort_by_key(|a| a.1);
         ret.truncate(count);
         ret
     }
@@ -0,0 +1,110 @@
+use super::storage::Record;
+use bincode::Options;
+use discv5::enr::{ed25519_dalek, k256, CombinedKey, CombinedPublicKey, EnrKey, EnrPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+
+// keys of signed records start with this prefix, nodes refuse to store them without a valid signature
+pub const SIGNED_KEY_PREFIX: &str = "pk/";
+
+// proof that the owner of the key published the value
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordSignature {
+    // compressed secp256k1 (33 bytes) or ed25519 (32 bytes) public key
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    // unix time the owner let the record live until, replicas never keep it longer. Signatures
+    // logged before it was signed don't verify anymore, they are kept until republished
+    #[serde(default = "never")]
+    pub expires: u64,
+}
+
+fn never() -> u64 {
+    u64::MAX
+}
+
+// the node key records are signed with, kept around to sign them again when they are republished
+#[derive(Clone)]
+pub struct Signer(Arc<CombinedKey>);
+
+impl Signer {
+    pub fn new(key: CombinedKey) -> Self {
+        Signer(Arc::new(key))
+    }
+
+    pub fn key(&self) -> &CombinedKey {
+        &self.0
+    }
+}
+
+// the secret key is never printed
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Signer").field(&key_for(&self.0.public())).finish()
+    }
+}
+
+// the key owned by a public key: the prefix followed by the hex encoded hash of the key
+pub fn key_for(public_key: &CombinedPublicKey) -> String {
+    let hash = Sha256::digest(&public_key.encode());
+    format!("{}{}", SIGNED_KEY_PREFIX, hex::encode(hash))
+}
+
+pub fn is_signed_key(key: &str) -> bool {
+    key.starts_with(SIGNED_KEY_PREFIX)
+}
+
+// the signature covers the key, the sequence number, whether it is a delete, the value and the expiry
+fn payload(key: &str, record: &Record, expires: u64) -> Vec<u8> {
+    bincode::DefaultOptions::new()
+        .serialize(&(key, record.version.seq, record.deleted, &record.value, expires))
+        .expect("[FAILED] Signed::payload --> Unable to serialize record")
+}
+
+fn decode_public_key(bytes: &[u8]) -> Option<CombinedPublicKey> {
+    match bytes.len() {
+        33 => k256::ecdsa::VerifyingKey::from_sec1_bytes(bytes)
+            .ok()
+            .map(CombinedPublicKey::from),
+        32 => {
+            let bytes: [u8; 32] = bytes.try_into().ok()?;
+            ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .ok()
+                .map(CombinedPublicKey::from)
+        }
+        _ => None,
+    }
+}
+
+pub fn sign(signer: &CombinedKey, key: &str, record: &Record) -> Option<RecordSignature> {
+    match signer.sign_v4(&payload(key, record, record.expires)) {
+        Ok(signature) => Some(RecordSignature {
+            public_key: signer.public().encode(),
+            signature,
+            expires: record.expires,
+        }),
+        Err(e) => {
+            eprintln!("[FAILED] Signed::sign --> Unable to sign record: {:?}", e);
+            None
+        }
+    }
+}
+
+// records under ordinary keys are always accepted, signed keys need a signature of their owner
+pub fn verify(key: &str, record: &Record) -> bool {
+    if !is_signed_key(key) {
+        return true;
+    }
+
+    let Some(signature) = &record.signature else {
+        return false;
+    };
+    let Some(public_key) = decode_public_key(&signature.public_key) else {
+        return false;
+    };
+
+    key_for(&public_key) == key
+        && public_key.verify_v4(&payload(key, record, signature.expires), &signature.signature)
+}
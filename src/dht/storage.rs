@@ -0,0 +1,485 @@
+use serde::{Deserialize, Serialize};
+use super::config::{MAX_CLOCK_SKEW, MAX_SEQ, RECORD_TTL, TOMBSTONE_TTL};
+use super::key::Key;
+use super::signed::RecordSignature;
+use super::utils;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// the log is rewritten once it holds this many entries and at least twice as many as live keys
+const COMPACTION_THRESHOLD: usize = 1024;
+
+// metadata deciding which of two values stored under the same key is the newest,
+// compared field by field: sequence number, then timestamp, then publisher id
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub seq: u64,
+    // unix time in milliseconds the value was published at
+    pub timestamp: u64,
+    pub publisher: Key,
+}
+
+impl Version {
+    pub fn new(seq: u64, publisher: Key) -> Self {
+        Self {
+            seq,
+            timestamp: now_millis(),
+            publisher,
+        }
+    }
+
+    // versions nobody could ever outrank are refused, the sequence number is capped and the
+    // timestamp can't be far ahead of our clock
+    pub fn is_plausible(&self) -> bool {
+        self.seq <= MAX_SEQ
+            && self.timestamp <= now_millis().saturating_add(MAX_CLOCK_SKEW * 1000)
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// a stored value along with the unix time (in seconds) after which it must be dropped
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    #[serde(with = "value_encoding")]
+    pub value: Vec<u8>,
+    pub expires: u64,
+    // full lifetime of the record, only set on the node that originally published it
+    #[serde(default)]
+    pub publish_ttl: Option<u64>,
+    // unix time we last published the record as its original publisher
+    #[serde(default)]
+    pub published_at: u64,
+    // unix time another node last stored the record on us
+    #[serde(default)]
+    pub received_at: u64,
+    // tombstone left by a delete, it shadows older values until it expires
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub version: Version,
+    // required for keys owned by a public key
+    #[serde(default)]
+    pub signature: Option<RecordSignature>,
+    // owner the record counts against in the store quotas, None for the ones we published
+    #[serde(default)]
+    pub charged_to: Option<String>,
+}
+
+impl Record {
+    pub fn new(value: Vec<u8>, ttl: u64, version: Version) -> Self {
+        Self {
+            value,
+            expires: utils::now_secs().saturating_add(ttl),
+            publish_ttl: None,
+            published_at: 0,
+            received_at: 0,
+            deleted: false,
+            version,
+            signature: None,
+            charged_to: None,
+        }
+    }
+
+    pub fn tombstone(ttl: u64, version: Version) -> Self {
+        Self {
+            deleted: true,
+            ..Self::new(Vec::new(), ttl, version)
+        }
+    }
+
+    // record kept by its original publisher, which republishes it with a fresh lifetime
+    pub fn published(value: Vec<u8>, ttl: u64, version: Version) -> Self {
+        Self {
+            publish_ttl: Some(ttl),
+            published_at: utils::now_secs(),
+            ..Self::new(value, ttl, version)
+        }
+    }
+
+    // conflict rule shared by every node: the higher version wins, ties are broken by
+    // preferring tombstones and then the greater value so replicas always converge
+    pub fn supersedes(&self, other: &Record) -> bool {
+        (&self.version, self.deleted, &self.value) > (&other.version, other.deleted, &other.value)
+    }
+
+    // records sent by other nodes can't outlive what a publisher is granted
+    pub fn cap_lifetime(mut self) -> Self {
+        let cap = if self.deleted { TOMBSTONE_TTL } else { RECORD_TTL };
+        self.expires = self.expires.min(utils::now_secs().saturating_add(cap));
+        // signed records don't outlive the expiry their owner signed
+        if let Some(signature) = &self.signature {
+            self.expires = self.expires.min(signature.expires);
+        }
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= utils::now_secs()
+    }
+
+    // remaining lifetime in seconds, sent over the wire instead of the absolute expiry
+    pub fn ttl(&self) -> u64 {
+        self.expires.saturating_sub(utils::now_secs())
+    }
+}
+
+// values are written to the log as {"hex": ...}, plain strings are still read back as UTF-8
+// so logs written before values became binary keep loading. Binary encodings get raw bytes
+mod value_encoding {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Encoded {
+        Hex { hex: String },
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            Encoded::Hex {
+                hex: hex::encode(value),
+            }
+            .serialize(serializer)
+        } else {
+            serializer.serialize_bytes(value)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Vec::deserialize(deserializer);
+        }
+
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Hex { hex } => hex::decode(hex).map_err(serde::de::Error::custom),
+            Encoded::Text(text) => Ok(text.into_bytes()),
+        }
+    }
+}
+
+// records and bytes stored for an owner, checked against the store quotas
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub records: usize,
+    pub bytes: u64,
+}
+
+// usage of every owner records are charged to, kept up to date by the backends on each insert and remove
+#[derive(Debug, Default)]
+struct OwnerUsage(HashMap<String, Usage>);
+
+impl OwnerUsage {
+    fn add(&mut self, record: &Record) {
+        let Some(owner) = &record.charged_to else {
+            return;
+        };
+        let usage = self.0.entry(owner.clone()).or_default();
+        usage.records += 1;
+        usage.bytes += record.value.len() as u64;
+    }
+
+    fn sub(&mut self, record: &Record) {
+        let Some(owner) = &record.charged_to else {
+            return;
+        };
+        if let Some(usage) = self.0.get_mut(owner) {
+            usage.records = usage.records.saturating_sub(1);
+            usage.bytes = usage.bytes.saturating_sub(record.value.len() as u64);
+            if usage.records == 0 {
+                self.0.remove(owner);
+            }
+        }
+    }
+
+    fn get(&self, owner: &str) -> Usage {
+        self.0.get(owner).copied().unwrap_or_default()
+    }
+}
+
+// backend holding the <key, record> pairs of a node
+pub trait Storage: Debug + Send {
+    fn get(&self, key: &str) -> Option<Record>;
+    fn insert(&mut self, key: String, record: Record) -> io::Result<()>;
+    fn remove(&mut self, key: &str) -> io::Result<Option<Record>>;
+    fn entries(&self) -> Vec<(String, Record)>;
+    fn usage(&self, owner: &str) -> Usage;
+
+    // makes the changes since the last call durable, nothing to do for backends that aren't
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // drops every expired record, returning how many were evicted
+    fn evict_expired(&mut self) -> io::Result<usize> {
+        let expired: Vec<String> = self
+            .entries()
+            .into_iter()
+            .filter(|(_, record)| record.is_expired())
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in &expired {
+            self.remove(key)?;
+        }
+
+        Ok(expired.len())
+    }
+}
+
+#[derive(Clone)]
+pub enum StorageKind {
+    Memory,
+    Disk,
+}
+
+impl std::fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageKind::Memory => f.write_str("memory"),
+            StorageKind::Disk => f.write_str("disk"),
+        }
+    }
+}
+
+impl std::str::FromStr for StorageKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageKind::Memory),
+            "disk" => Ok(StorageKind::Disk),
+            _ => Err("bad kind"),
+        }
+    }
+}
+
+pub fn open(kind: &StorageKind, path: &Path) -> io::Result<Box<dyn Storage>> {
+    match kind {
+        StorageKind::Memory => Ok(Box::new(MemoryStorage::new())),
+        StorageKind::Disk => Ok(Box::new(DiskStorage::open(path)?)),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: HashMap<String, Record>,
+    usage: OwnerUsage,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<Record> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: String, record: Record) -> io::Result<()> {
+        self.usage.add(&record);
+        if let Some(old) = self.entries.insert(key, record) {
+            self.usage.sub(&old);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<Option<Record>> {
+        let removed = self.entries.remove(key);
+        if let Some(old) = &removed {
+            self.usage.sub(old);
+        }
+        Ok(removed)
+    }
+
+    fn entries(&self) -> Vec<(String, Record)> {
+        self.entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn usage(&self, owner: &str) -> Usage {
+        self.usage.get(owner)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum LogEntry {
+    Put(String, Record),
+    Remove(String),
+}
+
+// append-only log of LogEntry lines, replayed on open and compacted once it grows too large.
+// Appends are buffered and only written through to disk by sync, so the runtime threads
+// never wait on an fsync
+#[derive(Debug)]
+pub struct DiskStorage {
+    path: PathBuf,
+    log: BufWriter<File>,
+    log_len: usize,
+    // whether entries were appended since the last sync
+    dirty: bool,
+    entries: HashMap<String, Record>,
+    usage: OwnerUsage,
+}
+
+impl DiskStorage {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut entries = HashMap::new();
+        let mut log_len = 0;
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            // split on raw bytes, a crash in the middle of an append leaves a torn last line
+            // behind which may end in the middle of a multi-byte character
+            for line in reader.split(b'\n') {
+                let line = line?;
+
+                match serde_json::from_slice::<LogEntry>(&line) {
+                    Ok(LogEntry::Put(k, v)) => {
+                        entries.insert(k, v);
+                    }
+                    Ok(LogEntry::Remove(k)) => {
+                        entries.remove(&k);
+                    }
+                    Err(_) => {
+                        eprintln!("[WARNING] DiskStorage::open --> Skipping corrupted log entry");
+                        continue;
+                    }
+                }
+                log_len += 1;
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+
+        let mut usage = OwnerUsage::default();
+        for record in entries.values() {
+            usage.add(record);
+        }
+
+        let mut storage = Self {
+            path: path.to_path_buf(),
+            log: BufWriter::new(log),
+            log_len,
+            dirty: false,
+            entries,
+            usage,
+        };
+        storage.compact()?;
+
+        Ok(storage)
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        self.log.write_all(line.as_bytes())?;
+        self.log_len += 1;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> io::Result<()> {
+        if self.log_len > COMPACTION_THRESHOLD && self.log_len > self.entries.len() * 2 {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    // rewrites the log with one entry per live key, swapping it in atomically
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (k, v) in &self.entries {
+            let line = serde_json::to_string(&LogEntry::Put(k.clone(), v.clone()))?;
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner()?.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        self.log_len = self.entries.len();
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn get(&self, key: &str) -> Option<Record> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: String, record: Record) -> io::Result<()> {
+        self.append(&LogEntry::Put(key.clone(), record.clone()))?;
+        self.usage.add(&record);
+        if let Some(old) = self.entries.insert(key, record) {
+            self.usage.sub(&old);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<Option<Record>> {
+        if !self.entries.contains_key(key) {
+            return Ok(None);
+        }
+
+        self.append(&LogEntry::Remove(key.to_string()))?;
+        let removed = self.entries.remove(key);
+        if let Some(old) = &removed {
+            self.usage.sub(old);
+        }
+
+        Ok(removed)
+    }
+
+    fn entries(&self) -> Vec<(String, Record)> {
+        self.entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn usage(&self, owner: &str) -> Usage {
+        self.usage.get(owner)
+    }
+
+    // a compaction rewrites the whole log, otherwise the buffered entries are flushed and synced
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.maybe_compact()?;
+        if self.dirty {
+            self.log.flush()?;
+            self.log.get_ref().sync_data()?;
+            self.dirty = false;
+        }
+
+        Ok(())
+    }
+}
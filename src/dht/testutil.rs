@@ -0,0 +1,41 @@
+// fixtures shared by the tests of the dht modules
+use super::network::WireFormat;
+use super::node::Node;
+use super::protocol::Protocol;
+use super::routing::IpLimits;
+use super::storage::{self, StorageKind};
+use crate::discovery::enr_builder::{DHT_ENR_KEY, DHT_IP_ENR_KEY};
+use discv5::enr::CombinedKey;
+use discv5::Enr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+// node with a fresh key, advertising its DHT on 127.0.0.1 and the given port
+pub fn make_node(port: u16) -> Node {
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(port)
+        .add_value(DHT_IP_ENR_KEY, &IpAddr::from([127, 0, 0, 1]))
+        .add_value(DHT_ENR_KEY, &port)
+        .build(&key)
+        .unwrap();
+    Node::new(enr, "127.0.0.1".to_string(), port)
+}
+
+// protocol listening on 127.0.0.1 and the given port, records are kept in memory
+pub async fn spawn_node(port: u16, bootstrap: Option<Node>) -> Protocol {
+    let store = storage::open(&StorageKind::Memory, Path::new("")).unwrap();
+
+    Protocol::new(
+        make_node(port),
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        bootstrap,
+        store,
+        WireFormat::Binary,
+        IpLimits::unlimited(),
+        None,
+    )
+    .await
+    .unwrap()
+}
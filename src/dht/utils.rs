@@ -3,43 +3,63 @@ use super::protocol::Protocol;
 
 use std::fs::create_dir_all;
 use std::io::Write;
-use std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::network;
 use super::routing::{KBucket, NodeAndDistance};
 
-#[derive(Debug)]
-pub enum ChannelPayload {
-    Request((network::Request, Node)),
-    Response(network::Response),
-    NoData,
-}
+// picks the address the DHT advertises without touching the network, in order:
+// the explicit one, the ENR ip4, the first non-loopback interface and finally loopback
+pub fn get_local_ip(explicit: Option<IpAddr>, enr_ip4: Option<Ipv4Addr>) -> IpAddr {
+    if let Some(ip) = explicit.filter(|ip| !ip.is_unspecified()) {
+        return ip;
+    }
 
-pub fn get_local_ip() -> Option<String> {
-    let socket = match UdpSocket::bind("0.0.0.0:0") {
-        Ok(s) => s,
-        Err(_) => return None,
-    };
+    if let Some(ip) = enr_ip4.filter(|ip| !ip.is_unspecified()) {
+        return IpAddr::V4(ip);
+    }
 
-    match socket.connect("8.8.8.8:80") {
-        Ok(()) => (),
-        Err(_) => return None,
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            eprintln!(
+                "[WARNING] Utils::get_local_ip --> Unable to list network interfaces: {}",
+                e
+            );
+            Vec::new()
+        }
     };
 
-    match socket.local_addr() {
-        Ok(addr) => return Some(addr.ip().to_string()),
-        Err(_) => return None,
-    };
+    // ipv4 first since it doesn't need a scope, then global ipv6
+    let candidate = interfaces
+        .iter()
+        .find(|i| !i.is_loopback() && i.ip().is_ipv4())
+        .or_else(|| {
+            interfaces
+                .iter()
+                .find(|i| !i.is_loopback() && !i.is_link_local())
+        });
+
+    match candidate {
+        Some(interface) => interface.ip(),
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
-pub fn make_req_get_res(
+pub async fn make_req_get_res(
     rpc: &network::Rpc,
     req: network::Request,
     dst: Node,
 ) -> Option<network::Response> {
-    rpc.make_request(req, dst)
-        .recv()
-        .expect("[FAILED] Utils::make_req_get_res --> Failed to receive response through channel")
+    rpc.make_request(req, dst).await
 }
 
 pub fn dump_interface_state(interface: &Protocol, path: &str) {
@@ -74,8 +94,8 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
 
     let mut parsed_store = vec![];
     // parse store
-    for (k, v) in &*st {
-        let obj = serde_json::json!({ k: v });
+    for (k, v) in st.entries() {
+        let obj = serde_json::json!({ k: String::from_utf8_lossy(&v.value), "expires": v.expires });
         parsed_store.push(obj);
     }
 
@@ -1,6 +1,9 @@
 use clap::Parser;
 use crate::SocketKind;
-use std:: net::{Ipv4Addr, Ipv6Addr};
+use crate::dht::network::WireFormat;
+use crate::dht::storage::StorageKind;
+use std:: net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 pub struct FindNodesArgs {
@@ -29,7 +32,45 @@ pub struct FindNodesArgs {
     pub remote_peer: Vec<discv5::Enr>,
     /// Use this option to turn on printing events received from discovery.
     #[clap(long)]
-    pub events: bool
+    pub events: bool,
+    /// Storage backend for the DHT records ['memory', 'disk'].
+    #[clap(long, default_value_t = StorageKind::Disk)]
+    pub storage: StorageKind,
+    /// Log file holding the DHT records when the disk storage backend is used.
+    #[clap(long, default_value = "data/store.log")]
+    pub storage_path: PathBuf,
+    /// Encoding of the DHT messages we send ['binary', 'json']. Both are always accepted.
+    #[clap(long, default_value_t = WireFormat::Binary)]
+    pub wire_format: WireFormat,
+    /// Accept any number of DHT contacts from the same /24 (IPv4) or /64 (IPv6). Only meant for
+    /// local testing with every node on the same host.
+    #[clap(long)]
+    pub no_ip_limits: bool,
+    /// File holding the secret key of the node, created on first run. The last advertised ENR
+    /// is kept next to it with an `.enr` extension.
+    #[clap(long, default_value = "data/node.key")]
+    pub key_file: PathBuf,
+    /// Ip the DHT socket binds. If none is provided, the advertised one is used.
+    #[clap(long)]
+    pub dht_ip: Option<IpAddr>,
+    /// Ip the DHT advertises to other nodes. If none is provided, it falls back to the bind ip,
+    /// the ENR ip4, the first non-loopback interface and finally loopback.
+    #[clap(long)]
+    pub dht_advertise_ip: Option<IpAddr>,
+    /// UDP port the DHT binds and advertises in the ENR. Defaults to 8001.
+    #[clap(long)]
+    pub dht_port: Option<u16>,
+    /// Address the HTTP API listens on. Defaults to 127.0.0.1:8080.
+    #[clap(long)]
+    pub http_addr: Option<SocketAddr>,
+    /// JSON file listing the ENRs of the bootstrap nodes. Defaults to bootstrap.json.
+    #[clap(long)]
+    pub bootstrap_file: Option<PathBuf>,
+    /// JSON config file providing `dht_ip`, `dht_advertise_ip`, `dht_port`, `http_addr` and
+    /// `bootstrap_file`.
+    /// Options given on the command line take precedence.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
 }
 
 pub fn parse_args() -> FindNodesArgs {
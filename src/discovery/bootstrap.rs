@@ -70,10 +70,10 @@ pub fn get_bootstrap_if_exists(file: Option<String>) -> Option<Node> {
 
         // Process the first node if available
         if let Some(first_node) = bootstrap_store.data.first() {
-            let enr = Enr::from_str(&first_node.enr);
-            let node_info = derive_info(&enr.unwrap());
-            if let Some(ip) = node_info.udp4{
-                let node = Node::new(ip.ip().to_string(), ip.port());
+            let enr = Enr::from_str(&first_node.enr).unwrap();
+            let node_info = derive_info(&enr);
+            if let Some(ip) = node_info.dht{
+                let node = Node::new(enr, ip.ip().to_string(), ip.port());
                 return Some(node)
             }else{
                return None
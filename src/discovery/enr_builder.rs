@@ -2,16 +2,28 @@
 use crate::discovery::args::FindNodesArgs;
 use crate::discovery::{parse_args};
 use discv5::{enr,enr::CombinedKey};
-use std:: net::{Ipv4Addr, Ipv6Addr};
+use std:: net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// ENR key holding the UDP port our DHT listens on
+pub const DHT_ENR_KEY: &str = "dht";
+
+/// ENR key holding the ip our DHT advertises, which may differ from the discv5 one
+pub const DHT_IP_ENR_KEY: &str = "dht_ip";
 
 pub fn build_enr(
     args: &FindNodesArgs,
     enr_key: &CombinedKey,
     port: u16,
     port6: u16,
+    dht_ip: IpAddr,
+    dht_port: u16,
 ) -> enr::Enr<CombinedKey> {
     // Clone the key to use in the ENR builder
     let mut builder = enr::Enr::builder();
+    // Advertising the DHT address so peers don't have to guess it from the discv5 one,
+    // they only add us when it is the one our messages come from
+    builder.add_value(DHT_IP_ENR_KEY, &dht_ip);
+    builder.add_value(DHT_ENR_KEY, &dht_port);
     if let Some(ip4) = args.enr_ip4 {
         if ip4.is_unspecified() {
             builder.ip4(Ipv4Addr::LOCALHOST).udp4(port);
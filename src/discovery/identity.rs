@@ -0,0 +1,78 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use discv5::enr::CombinedKey;
+use discv5::Enr;
+use tracing::info;
+
+/// Loads the secp256k1 key of the node, generating and saving a fresh one on first run.
+pub fn load_or_create_key(path: &Path) -> io::Result<CombinedKey> {
+    if path.exists() {
+        let encoded = fs::read_to_string(path)?;
+        let mut bytes = hex::decode(encoded.trim())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let key = CombinedKey::secp256k1_from_bytes(&mut bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        info!("Loaded node key from {}", path.display());
+        return Ok(key);
+    }
+
+    let key = CombinedKey::generate_secp256k1();
+    write_private(path, hex::encode(key.encode()).as_bytes())?;
+    info!("Generated new node key in {}", path.display());
+    Ok(key)
+}
+
+/// Reuses the sequence number of the last ENR we advertised, bumping it if the content changed,
+/// and saves the result for the next run.
+pub fn persist_seq(enr: &mut Enr, key: &CombinedKey, path: &Path) -> io::Result<()> {
+    if let Some(saved) = load_enr(path) {
+        if saved.node_id() == enr.node_id() {
+            set_seq(enr, key, saved.seq())?;
+            if !enr.compare_content(&saved) {
+                set_seq(enr, key, saved.seq() + 1)?;
+                info!(seq = enr.seq(), "Local ENR changed since last run");
+            }
+        }
+    }
+
+    save_enr(path, enr)
+}
+
+pub fn save_enr(path: &Path, enr: &Enr) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, enr.to_base64())
+}
+
+fn load_enr(path: &Path) -> Option<Enr> {
+    let encoded = fs::read_to_string(path).ok()?;
+    Enr::from_str(encoded.trim()).ok()
+}
+
+fn set_seq(enr: &mut Enr, key: &CombinedKey, seq: u64) -> io::Result<()> {
+    enr.set_seq(seq, key)
+        .map_err(|e| io::Error::other(format!("{:?}", e)))
+}
+
+// the key file must only be readable by its owner
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
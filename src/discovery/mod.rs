@@ -3,6 +3,8 @@ pub mod enr_builder;
 pub mod service;
 pub mod socket;
 pub mod bootstrap;
+pub mod identity;
+pub mod settings;
 pub use args::{FindNodesArgs, parse_args};
 pub use enr_builder::build_enr;
 pub use service::{start_discv5_service, lookup_nodes};
@@ -1,6 +1,8 @@
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
 
-use crate::{dht::protocol::Protocol, info, warn};
+use super::enr_builder::{DHT_ENR_KEY, DHT_IP_ENR_KEY};
+
+use crate::{dht::config::{RECORD_TTL, WRITE_QUORUM}, dht::protocol::Protocol, info, warn};
 use discv5::{
     enr::{self, CombinedKey, NodeId},
     Discv5,
@@ -9,6 +11,8 @@ use discv5::{
 pub struct NodeInfo {
     pub node_id: NodeId,
     pub udp4: Option<SocketAddrV4>,
+    /// Endpoint of the DHT, built from the ip and port advertised under the `dht_ip` and `dht` keys
+    pub dht: Option<SocketAddr>,
 }
 
 pub async fn start_discv5_service(
@@ -83,7 +87,13 @@ pub async fn talk(
                     println!("Received peer size from {}: {}", remote_node_id, remote_peer_size);
 
                     // Optionally update interface or perform additional actions
-                    interface.put(remote_node_id.to_string(), remote_peer_size.to_string());
+                    // the write runs in the background so the sweep over our peers isn't held up
+                    interface.spawn_put(
+                        remote_node_id.to_string(),
+                        remote_peer_size.to_string().into_bytes(),
+                        RECORD_TTL,
+                        WRITE_QUORUM,
+                    );
                 }
             }
             Ok(())
@@ -96,9 +106,18 @@ pub async fn talk(
 pub fn derive_info(enr: &enr::Enr<CombinedKey>) -> NodeInfo {
     let node_id = enr.node_id();
     let udp_port = enr.udp4_socket();
+    let dht_port = match enr.get_decodable::<u16>(DHT_ENR_KEY) {
+        Some(Ok(port)) => Some(port),
+        _ => None,
+    };
+    let dht = match (enr.get_decodable::<IpAddr>(DHT_IP_ENR_KEY), dht_port) {
+        (Some(Ok(ip)), Some(port)) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    };
 
     NodeInfo {
-        node_id: node_id,
+        node_id,
         udp4: udp_port,
+        dht,
     }
 }
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::args::FindNodesArgs;
+
+pub const DEFAULT_DHT_PORT: u16 = 8001;
+pub const DEFAULT_HTTP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+pub const DEFAULT_BOOTSTRAP_FILE: &str = "bootstrap.json";
+
+/// Optional JSON file with the same settings as the command line, which takes precedence.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub dht_ip: Option<IpAddr>,
+    pub dht_advertise_ip: Option<IpAddr>,
+    pub dht_port: Option<u16>,
+    pub http_addr: Option<SocketAddr>,
+    pub bootstrap_file: Option<PathBuf>,
+}
+
+/// Addresses the node binds and the bootstrap file it reads, resolved from the args and config file.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Ip the DHT socket binds, the advertised one is used when none is given.
+    pub dht_ip: Option<IpAddr>,
+    /// Ip the DHT advertises, detected when none is given.
+    pub dht_advertise_ip: Option<IpAddr>,
+    pub dht_port: u16,
+    pub http_addr: SocketAddr,
+    pub bootstrap_file: PathBuf,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("unable to open config file {}: {}", path.display(), e),
+            )
+        })?;
+
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config file {}: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+pub fn resolve(args: &FindNodesArgs, discv5_ports: &[u16]) -> io::Result<Settings> {
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+
+    let settings = Settings {
+        dht_ip: args.dht_ip.or(file.dht_ip),
+        dht_advertise_ip: args.dht_advertise_ip.or(file.dht_advertise_ip),
+        dht_port: args.dht_port.or(file.dht_port).unwrap_or(DEFAULT_DHT_PORT),
+        http_addr: args
+            .http_addr
+            .or(file.http_addr)
+            .unwrap_or(DEFAULT_HTTP_ADDR),
+        bootstrap_file: args
+            .bootstrap_file
+            .clone()
+            .or(file.bootstrap_file)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_BOOTSTRAP_FILE)),
+    };
+
+    validate(&settings, discv5_ports)?;
+    Ok(settings)
+}
+
+fn validate(settings: &Settings, discv5_ports: &[u16]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    // the DHT port is advertised in our ENR, so it has to be a fixed one
+    if settings.dht_port == 0 {
+        return Err(invalid("the DHT port must not be 0".to_string()));
+    }
+    if discv5_ports.contains(&settings.dht_port) {
+        return Err(invalid(format!(
+            "the DHT port {} is already used by discv5",
+            settings.dht_port
+        )));
+    }
+    if settings.http_addr.port() == 0 {
+        return Err(invalid("the HTTP port must not be 0".to_string()));
+    }
+    if let Some(ip) = settings.dht_ip {
+        if ip.is_multicast() {
+            return Err(invalid(format!("the DHT ip {} is a multicast address", ip)));
+        }
+    }
+    if let Some(ip) = settings.dht_advertise_ip {
+        if ip.is_unspecified() || ip.is_multicast() {
+            return Err(invalid(format!("the DHT can't advertise {}", ip)));
+        }
+    }
+
+    Ok(())
+}
@@ -6,28 +6,114 @@ mod discovery;
 use discovery::args::parse_args;
 use discovery::bootstrap::{boostrap, get_bootstrap_if_exists};
 use discovery::enr_builder::build_enr;
+use discovery::identity;
+use discovery::settings;
 use discovery::service::{derive_info, lookup_nodes, start_discv5_service, derive_id_from_enr, talk};
 use discovery::SocketKind;
 use discv5::enr::k256::pkcs8::der::Encode;
-use discv5::{enr::CombinedKey, ConfigBuilder, Discv5, Event, ListenConfig};
+use discv5::{ConfigBuilder, Discv5, Event, ListenConfig};
 
 //DHT packages
 mod dht;
+use dht::config::{READ_QUORUM, RECORD_TTL, REPLICATION_FACTOR, WRITE_QUORUM};
+use dht::content;
 use dht::node::Node;
-use dht::protocol::Protocol;
+use dht::protocol::{Protocol, Quorum};
+use dht::routing::IpLimits;
+use dht::signed::Signer;
+use dht::storage;
 use dht::utils;
 
 //Data
 mod datatypes;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
-use datatypes::requests::{RetrieveRequest, StoreRequest};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer, Responder};
+use datatypes::requests::{
+    RawStoreQuery, ReadQuery, RetrieveRequest, SignedStoreRequest, StoreRequest, WriteQuery,
+};
+use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
-use tracing::{info, warn};
+use tracing::{error, info, warn};
+
+//Largest body accepted by the HTTP API, large values are chunked across the DHT
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+//Header carrying the number of replicas that acknowledged a write or answered a read
+const ACKS_HEADER: &str = "x-replica-acks";
+
+//A quorum above the replication factor could never be met
+fn check_quorum(quorum: usize) -> Option<HttpResponse> {
+    if quorum == 0 || quorum > REPLICATION_FACTOR {
+        return Some(HttpResponse::BadRequest().json(format!(
+            "Quorum must be between 1 and {}",
+            REPLICATION_FACTOR
+        )));
+    }
+    None
+}
+
+//Records expire after RECORD_TTL at the latest, other nodes cap anything longer
+fn check_ttl(ttl: u64) -> Option<HttpResponse> {
+    if ttl > RECORD_TTL {
+        return Some(HttpResponse::BadRequest().json(format!(
+            "Time-to-live must be at most {} seconds",
+            RECORD_TTL
+        )));
+    }
+    None
+}
+
+fn with_acks(mut res: HttpResponse, quorum: &Quorum) -> HttpResponse {
+    res.headers_mut().insert(
+        HeaderName::from_static(ACKS_HEADER),
+        HeaderValue::from(quorum.acks),
+    );
+    res
+}
+
+//Writes succeed once enough replicas acknowledged them, None means the key can't be written this way
+fn write_response(quorum: Option<Quorum>, success: &str, reserved: &str) -> HttpResponse {
+    match quorum {
+        Some(quorum) => quorum_response(quorum, success),
+        None => HttpResponse::BadRequest().json(reserved),
+    }
+}
+
+fn quorum_response(quorum: Quorum, success: &str) -> HttpResponse {
+    let res = if quorum.is_met() {
+        HttpResponse::Ok().json(success)
+    } else {
+        HttpResponse::ServiceUnavailable().json(format!(
+            "Write quorum not met: {} of {} replicas acknowledged",
+            quorum.acks, quorum.required
+        ))
+    };
+    with_acks(res, &quorum)
+}
+
+//Reads fail when some replicas answered but not enough of them, the value is rendered by the caller
+fn read_response(
+    (value, quorum): (Option<Vec<u8>>, Quorum),
+    render: impl FnOnce(Vec<u8>) -> HttpResponse,
+) -> HttpResponse {
+    let res = match value {
+        _ if quorum.acks > 0 && !quorum.is_met() => {
+            HttpResponse::ServiceUnavailable().json(format!(
+                "Read quorum not met: {} of {} replicas answered",
+                quorum.acks, quorum.required
+            ))
+        }
+        Some(value) => render(value),
+        None => HttpResponse::NotFound().json("Data not found"),
+    };
+    with_acks(res, &quorum)
+}
 
 #[get("/")]
 async fn hello() -> impl Responder {
@@ -38,30 +124,177 @@ async fn hello() -> impl Responder {
 #[post("/store")]
 async fn store_data(
     data: web::Json<StoreRequest>,
+    query: web::Query<WriteQuery>,
     dht: web::Data<Arc<Protocol>>,
 ) -> impl Responder {
+    let write_quorum = query.w.unwrap_or(WRITE_QUORUM);
+    if let Some(res) = check_quorum(write_quorum) {
+        return res;
+    }
+    let ttl = data.ttl.unwrap_or(RECORD_TTL);
+    if let Some(res) = check_ttl(ttl) {
+        return res;
+    }
     let new_store = StoreRequest {
         key: data.key.clone(),
-        value: data.value.clone()
+        value: data.value.clone(),
+        ttl: data.ttl,
     };
     info!("Received store request {} {}", new_store.key, new_store.value);
-    dht.put(new_store.key, new_store.value);
-    HttpResponse::Ok().json("Data stored successfully")
+    let quorum = dht
+        .put(
+            new_store.key,
+            new_store.value.into_bytes(),
+            ttl,
+            write_quorum,
+        )
+        .await;
+    write_response(
+        quorum,
+        "Data stored successfully",
+        "Key is reserved, use /signed or /blobs",
+    )
+}
+
+//Stores a value under the key owned by the node key, only this node can update it
+#[post("/signed")]
+async fn store_signed_data(
+    data: web::Json<SignedStoreRequest>,
+    dht: web::Data<Arc<Protocol>>,
+) -> impl Responder {
+    info!("Received signed store request {}", data.value);
+    let ttl = data.ttl.unwrap_or(RECORD_TTL);
+    if let Some(res) = check_ttl(ttl) {
+        return res;
+    }
+    match dht
+        .put_signed(data.value.clone().into_bytes(), ttl)
+        .await
+    {
+        Some(key) => HttpResponse::Ok().json(key),
+        None => HttpResponse::InternalServerError().json("Unable to sign the value"),
+    }
+}
+
+//Stores the body as an immutable value, the hex encoded SHA-256 hash it is stored under is returned
+#[post("/blobs")]
+async fn store_blob(
+    body: web::Bytes,
+    query: web::Query<WriteQuery>,
+    dht: web::Data<Arc<Protocol>>,
+) -> impl Responder {
+    let write_quorum = query.w.unwrap_or(WRITE_QUORUM);
+    if let Some(res) = check_quorum(write_quorum) {
+        return res;
+    }
+    info!("Received blob of {} bytes", body.len());
+    let (hash, quorum) = dht.put_immutable(body.to_vec(), write_quorum).await;
+    quorum_response(quorum, &hex::encode(hash.0))
+}
+
+#[get("/blobs/{hash}")]
+async fn retrieve_blob(hash: web::Path<String>, dht: web::Data<Arc<Protocol>>) -> impl Responder {
+    let Some(hash) = content::parse_hash(&hash) else {
+        return HttpResponse::BadRequest().json("Invalid hash");
+    };
+    match dht.get_immutable(&hash).await {
+        Some(value) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(value),
+        None => HttpResponse::NotFound().json("Data not found"),
+    }
 }
 
 #[post("/retrieve")]
 async fn retrieve_data(
     data: web::Json<RetrieveRequest>,
+    query: web::Query<ReadQuery>,
     dht: web::Data<Arc<Protocol>>,
 ) -> impl Responder {
     info!("Received get request");
-    match dht.get(data.key.clone()) {
-        Some(value) => HttpResponse::Ok().json(value),
-        None => HttpResponse::NotFound().json("Data not found"),
+    let read_quorum = query.r.unwrap_or(READ_QUORUM);
+    if let Some(res) = check_quorum(read_quorum) {
+        return res;
+    }
+    read_response(dht.get(data.key.clone(), read_quorum).await, |value| {
+        match String::from_utf8(value) {
+            Ok(value) => HttpResponse::Ok().json(value),
+            Err(_) => HttpResponse::UnprocessableEntity()
+                .json("Value is not valid UTF-8, use GET /keys/{key}"),
+        }
+    })
+}
+
+//Raw values: the body is stored as is and returned verbatim
+#[put("/keys/{key:.*}")]
+async fn store_raw_data(
+    key: web::Path<String>,
+    query: web::Query<RawStoreQuery>,
+    body: web::Bytes,
+    dht: web::Data<Arc<Protocol>>,
+) -> impl Responder {
+    info!("Received raw store request {} of {} bytes", key, body.len());
+    let write_quorum = query.w.unwrap_or(WRITE_QUORUM);
+    if let Some(res) = check_quorum(write_quorum) {
+        return res;
     }
+    let ttl = query.ttl.unwrap_or(RECORD_TTL);
+    if let Some(res) = check_ttl(ttl) {
+        return res;
+    }
+    let quorum = dht
+        .put(
+            key.into_inner(),
+            body.to_vec(),
+            ttl,
+            write_quorum,
+        )
+        .await;
+    write_response(
+        quorum,
+        "Data stored successfully",
+        "Key is reserved, use /signed or /blobs",
+    )
 }
 
-async fn run_discovery_loop(discv5: Discv5, interface: Arc<Protocol>) {
+#[get("/keys/{key:.*}")]
+async fn retrieve_raw_data(
+    key: web::Path<String>,
+    query: web::Query<ReadQuery>,
+    dht: web::Data<Arc<Protocol>>,
+) -> impl Responder {
+    info!("Received raw get request {}", key);
+    let read_quorum = query.r.unwrap_or(READ_QUORUM);
+    if let Some(res) = check_quorum(read_quorum) {
+        return res;
+    }
+    read_response(dht.get(key.into_inner(), read_quorum).await, |value| {
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(value)
+    })
+}
+
+#[delete("/keys/{key:.*}")]
+async fn delete_data(
+    key: web::Path<String>,
+    query: web::Query<WriteQuery>,
+    dht: web::Data<Arc<Protocol>>,
+) -> impl Responder {
+    let write_quorum = query.w.unwrap_or(WRITE_QUORUM);
+    if let Some(res) = check_quorum(write_quorum) {
+        return res;
+    }
+    info!("Received delete request {}", key);
+    let quorum = dht.remove(key.into_inner(), write_quorum).await;
+    write_response(
+        quorum,
+        "Data deleted successfully",
+        "Signed and content-addressed keys can't be deleted",
+    )
+}
+
+async fn run_discovery_loop(discv5: Discv5, interface: Arc<Protocol>, enr_file: PathBuf) {
     let mut event_stream = discv5.event_stream().await.unwrap();
 
     // construct a 30 second interval to search for new peers.
@@ -93,34 +326,42 @@ async fn run_discovery_loop(discv5: Discv5, interface: Arc<Protocol>) {
                         info!(%enr, "Enr discovered");
                         //Pinging new discovered node to store it in our dht
                         let enr_info = derive_info(&enr);
-                        if let Some(ip) = enr_info.udp4{
-                            let node = Node::new(ip.ip().to_string(), ip.port() + 1);
-                            //Storing the new node by pinging it
-                            let res = interface.ping(node);
-                            //Mapping the nodeId to the current ENR for later use in case ENR is updated
-                            let id = derive_id_from_enr(&enr);
-                            if let Some(node_id) = id {
-                                interface.put(node_id.to_string(), 0.to_string()); //Initializing known peers to 0 
-                                info!("ENR mapped to node ID successfully");
-                            }
-                            if res{
-                                info!("Node stored under our DHT successfully");
-                            }else{
-                                info!("Failed at receiving PONG response. Node not stored.");
+                        if let Some(ip) = enr_info.dht{
+                            let node = Node::new(enr.clone(), ip.ip().to_string(), ip.port());
+                            //Subnets already holding too many of our contacts are left out so a single host cannot fill our buckets
+                            if !interface.admits(&node) {
+                                info!(ip = %node.ip, "Too many DHT contacts from the same subnet. Node not stored.");
+                            } else {
+                                //Storing the new node by pinging it
+                                let res = interface.ping(node).await;
+                                //Mapping the nodeId to the current ENR for later use in case ENR is updated
+                                let id = derive_id_from_enr(&enr);
+                                if let Some(node_id) = id {
+                                    interface.spawn_put(node_id.to_string(), 0.to_string().into_bytes(), RECORD_TTL, WRITE_QUORUM); //Initializing known peers to 0 
+                                    info!("ENR mapped to node ID successfully");
+                                }
+                                if res{
+                                    info!("Node stored under our DHT successfully");
+                                }else{
+                                    info!("Failed at receiving PONG response. Node not stored.");
+                                }
                             }
                         }else{
-                            info!("Could not derive node information")
+                            info!(udp4 = ?enr_info.udp4, "Could not derive node information, no DHT endpoint advertised")
                         }
                     },
                     Event::NodeInserted { node_id, replaced: _ } => info!(%node_id, "Node inserted"), //derive
                     Event::SessionEstablished(enr, _) => info!(%enr, "Session established"),
                     Event::SocketUpdated(addr) => {
                         info!(%addr, "Socket updated"); //Find key in dht and update addr + port
-                        let ip = addr.ip().to_string();
-                        let port = addr.port();
-                        let node = Node::new(ip, port);
-                        interface.ping(node); //Pinging node to add it to dht 
-                        //Old node will automatically be placed at the bottom of the routing table queue and be left out because of being inactive 
+                        //Discv5 bumped the sequence number of our ENR, saving it for the next run
+                        if let Err(e) = identity::save_enr(&enr_file, &discv5.local_enr()) {
+                            warn!(error = ?e, "Failed to save local ENR");
+                        }
+                        //Our DHT messages carry the new ENR from now on, peers replace the old one as they hear from us
+                        if !interface.rpc.set_local_enr(discv5.local_enr()) {
+                            warn!("Discv5 handed out an ENR older than the one the DHT advertises");
+                        }
                     },
                     //Performing a talk request to keep up to date information on the connected peers from a node and storing it under our dht 
                     Event::TalkRequest(talk_request) => {
@@ -145,7 +386,7 @@ async fn run_discovery_loop(discv5: Discv5, interface: Arc<Protocol>) {
                             let node_id_str = parts[0].to_string();
                             let known_peers_remote = parts[1].to_string();
                             info!("talk request received from peer {}", node_id_str);
-                            interface.put(node_id_str, known_peers_remote); // Storing known peer size to node ID 
+                            interface.spawn_put(node_id_str, known_peers_remote.into_bytes(), RECORD_TTL, WRITE_QUORUM); // Storing known peer size to node ID 
 
                             let known_peers = discv5.connected_peers();
                             let self_id = discv5.local_enr().id();
@@ -166,7 +407,7 @@ async fn run_discovery_loop(discv5: Discv5, interface: Arc<Protocol>) {
 
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> io::Result<()> {
     let filter_layer = tracing_subscriber::EnvFilter::try_from_default_env()
         .or_else(|_| tracing_subscriber::EnvFilter::try_new("info"))
         .unwrap();
@@ -186,10 +427,26 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
-    //Generating fresh ENR for node
-    let enr_key = CombinedKey::generate_secp256k1();
+    //Resolving bind addresses from the args and the optional config file
+    let settings = settings::resolve(&args, &[port, port6]).map_err(|e| {
+        error!("Invalid settings: {}", e);
+        e
+    })?;
+
+    //Loading the node key so our identity survives restarts
+    let enr_key = identity::load_or_create_key(&args.key_file)?;
+    //Second handle on the node key used to sign records, discv5 takes ownership of the first one
+    let signer = Signer::new(identity::load_or_create_key(&args.key_file)?);
+
+    //Resolving the ip the DHT advertises first, it is part of our ENR
+    let advertised_ip = utils::get_local_ip(
+        settings.dht_advertise_ip.or(settings.dht_ip),
+        args.enr_ip4,
+    );
 
-    let enr = build_enr(&args, &enr_key, port, port6);
+    let mut enr = build_enr(&args, &enr_key, port, port6, advertised_ip, settings.dht_port);
+    let enr_file = args.key_file.with_extension("enr");
+    identity::persist_seq(&mut enr, &enr_key, &enr_file)?;
     // the address to listen on.
     let listen_config = match args.socket_kind {
         SocketKind::Ip4 => ListenConfig::from_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
@@ -216,40 +473,78 @@ async fn main() -> std::io::Result<()> {
     }
 
     //Initializing discv5 server
-    let mut discv5 = start_discv5_service(enr, enr_key, config).await;
+    let mut discv5 = start_discv5_service(enr.clone(), enr_key, config).await;
     // construct the discv5 server
-    let bootstrap_file = Some("bootstrap.json".to_string());
+    let bootstrap_file = Some(settings.bootstrap_file.to_string_lossy().to_string());
     // if we know of another peer's ENR, add it known peers -> Bootstrap process
-    boostrap(&mut discv5, bootstrap_file.clone()).await.unwrap();
+    if let Err(e) = boostrap(&mut discv5, bootstrap_file.clone()).await {
+        warn!(error = %e, "Failed to bootstrap from {}", settings.bootstrap_file.display());
+    }
 
     // start the discv5 service
     discv5.start().await.unwrap();
 
-    //Using bootstrap.json to get an optional Node that we pass to our interface to bootstrap
+    //Using the bootstrap file to get an optional Node that we pass to our interface to bootstrap
     let bootstrap_result = get_bootstrap_if_exists(bootstrap_file);
-    //Starting root with local ip address and port + 1
-    let root = Node::new(utils::get_local_ip().unwrap(), 8001);
+    //Starting root with the advertised ip address and DHT port, its DHT id is the node id of our ENR
+    let bind_addr = SocketAddr::new(settings.dht_ip.unwrap_or(advertised_ip), settings.dht_port);
+    info!(%advertised_ip, %bind_addr, "DHT address");
+    let root = Node::new(enr, advertised_ip.to_string(), settings.dht_port);
+
+    //Opening the record storage selected through the args, persisted records are loaded back
+    let store = storage::open(&args.storage, &args.storage_path)?;
+
+    //Limiting the contacts accepted from a single subnet, unless every node runs on this host
+    let ip_limits = if args.no_ip_limits {
+        warn!("IP limits disabled, any number of DHT contacts is accepted from the same subnet");
+        IpLimits::unlimited()
+    } else {
+        IpLimits::default()
+    };
 
     //DHT interface responsible for adding nodes and data
-    let dht_protocol = Arc::new(Protocol::new(
-        root.ip.clone(),
-        root.port.clone(),
-        bootstrap_result,
-    ));
+    let dht_protocol = Arc::new(
+        Protocol::new(
+            root,
+            bind_addr,
+            bootstrap_result,
+            store,
+            args.wire_format,
+            ip_limits,
+            Some(signer),
+        )
+        .await
+            .map_err(|e| {
+                error!("Failed to start the DHT: {}", e);
+                e
+            })?,
+    );
 
     // Clone the Arc for use in the discovery loop on a separate thread for shared state
     let dht_protocol_for_loop = dht_protocol.clone();
-    tokio::spawn(run_discovery_loop( discv5, dht_protocol_for_loop));
+    tokio::spawn(run_discovery_loop(discv5, dht_protocol_for_loop, enr_file));
 
     //Exposing external api to interact with the dht
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(dht_protocol.clone()))
+            .app_data(web::PayloadConfig::new(MAX_BODY_SIZE))
+            .app_data(web::JsonConfig::default().limit(MAX_BODY_SIZE))
             .service(store_data)
+            .service(store_signed_data)
+            .service(store_blob)
+            .service(retrieve_blob)
             .service(retrieve_data)
+            .service(store_raw_data)
+            .service(retrieve_raw_data)
+            .service(delete_data)
             .service(hello)
     })
-    .bind(("127.0.0.1", 8080))?
+    .bind(settings.http_addr)
+    .map_err(|e| {
+        error!("Failed to bind the HTTP API on {}: {}", settings.http_addr, e);
+        e
+    })?
     .run()
     .await
 }